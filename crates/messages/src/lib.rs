@@ -0,0 +1,874 @@
+use std::io::Read;
+use byteorder::{LE, ReadBytesExt, WriteBytesExt};
+
+// Wire formats for the events the overlay consumes.
+// Numbers are little-endian, and "prefixed" strings carry a u32 length.
+
+#[derive(Debug)]
+pub enum Error {
+    UnknownEvent(String),
+    NotEnoughFields,
+    BadColor(String),
+    BadDuration(String),
+    BadField(String),
+    Io(std::io::Error),
+    Utf8(std::string::FromUtf8Error),
+    ParseInt(std::num::ParseIntError),
+    ParseFloat(std::num::ParseFloatError),
+}
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownEvent(ev) => write!(f, "unknown event: {}", ev),
+            Self::NotEnoughFields => write!(f, "not enough fields"),
+            Self::BadColor(c) => write!(f, "bad color: {}", c),
+            Self::BadDuration(d) => write!(f, "bad duration: {}", d),
+            Self::BadField(d) => write!(f, "bad field: {}", d),
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Utf8(e) => write!(f, "{}", e),
+            Self::ParseInt(e) => write!(f, "{}", e),
+            Self::ParseFloat(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl std::error::Error for Error {}
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self { Self::Io(e) }
+}
+impl From<std::string::FromUtf8Error> for Error {
+    fn from(e: std::string::FromUtf8Error) -> Self { Self::Utf8(e) }
+}
+impl From<std::num::ParseIntError> for Error {
+    fn from(e: std::num::ParseIntError) -> Self { Self::ParseInt(e) }
+}
+impl From<std::num::ParseFloatError> for Error {
+    fn from(e: std::num::ParseFloatError) -> Self { Self::ParseFloat(e) }
+}
+
+fn read_prefixed<R: Read>(r: &mut R) -> Result<Vec<u8>, Error> {
+    let len = r.read_u32::<LE>()? as usize;
+    let mut ret = Vec::new();
+    r.take(len as u64).read_to_end(&mut ret)?;
+    if ret.len() != len { return Err(Error::NotEnoughFields) }
+    Ok(ret)
+}
+fn read_prefixed_utf8<R: Read>(r: &mut R) -> Result<String, Error> {
+    Ok(String::from_utf8(read_prefixed(r)?)?)
+}
+fn write_prefixed(w: &mut Vec<u8>, bs: &[u8]) {
+    w.write_u32::<LE>(bs.len() as u32).expect("write to Vec failed");
+    w.extend_from_slice(bs);
+}
+fn utf8(data: &[u8]) -> Result<String, Error> {
+    Ok(String::from_utf8(data.to_vec())?)
+}
+/// A duration such as "30s", "500ms", "2m" or a bare number of seconds.
+fn parse_duration(s: &str) -> Result<f32, Error> {
+    let (num, scale) = if let Some(n) = s.strip_suffix("ms") { (n, 0.001) }
+        else if let Some(n) = s.strip_suffix('s') { (n, 1.0) }
+        else if let Some(n) = s.strip_suffix('m') { (n, 60.0) }
+        else if let Some(n) = s.strip_suffix('h') { (n, 3600.0) }
+        else { (s, 1.0) };
+    let n: f32 = num.parse().map_err(|_| Error::BadDuration(s.to_owned()))?;
+    if !n.is_finite() || n < 0.0 { return Err(Error::BadDuration(s.to_owned())) }
+    Ok(n * scale)
+}
+
+/// A toggle change, optionally reverted after a while.
+/// On the wire this is the toggle name, then an optional duration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToggleChange {
+    pub name: String,
+    pub duration: Option<f32>, // seconds
+}
+impl ToggleChange {
+    pub fn decode(s: &str) -> Result<Self, Error> {
+        let mut sp = s.split_whitespace();
+        Ok(Self {
+            name: sp.next().ok_or(Error::NotEnoughFields)?.to_owned(),
+            duration: sp.next().map(parse_duration).transpose()?,
+        })
+    }
+    pub fn encode(&self) -> String {
+        match self.duration {
+            Some(d) => format!("{} {}s", self.name, d),
+            None => self.name.clone(),
+        }
+    }
+}
+
+/// A typed overlay parameter. In text, colors are "#rrggbb" or "#rrggbbaa",
+/// strings are double-quoted (or a bare word), and numbers with a decimal
+/// point are floats.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParamValue {
+    Float(f32),
+    Int(i64),
+    Color([u8; 4]),
+    String(String),
+}
+impl ParamValue {
+    fn parse_color(s: &str) -> Result<[u8; 4], Error> {
+        let hex = s.strip_prefix('#').filter(|h| (h.len() == 6 || h.len() == 8) && h.is_ascii())
+            .ok_or_else(|| Error::BadColor(s.to_owned()))?;
+        let byte = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16);
+        Ok([byte(0)?, byte(2)?, byte(4)?, if hex.len() == 8 { byte(6)? } else { 0xff }])
+    }
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        if s.starts_with('#') { return Ok(Self::Color(Self::parse_color(s)?)) }
+        if let Some(q) = s.strip_prefix('"').and_then(|q| q.strip_suffix('"')) { return Ok(Self::String(q.to_owned())) }
+        if let Ok(i) = s.parse() { return Ok(Self::Int(i)) }
+        if let Ok(f) = s.parse() { return Ok(Self::Float(f)) }
+        Ok(Self::String(s.to_owned()))
+    }
+}
+impl std::fmt::Display for ParamValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Float(x) => write!(f, "{:?}", x),
+            Self::Int(i) => write!(f, "{}", i),
+            Self::Color(c) => write!(f, "#{:02x}{:02x}{:02x}{:02x}", c[0], c[1], c[2], c[3]),
+            Self::String(s) => write!(f, "\"{}\"", s),
+        }
+    }
+}
+
+/// A parameter change, interpolated over an optional duration.
+/// On the wire this is the name, the value and then the duration,
+/// e.g. `shader_opacity 0.8 2s`. Strings cannot contain double quotes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamChange {
+    pub name: String,
+    pub value: ParamValue,
+    pub duration: Option<f32>, // seconds
+}
+impl ParamChange {
+    pub fn decode(s: &str) -> Result<Self, Error> {
+        let s = s.trim_start();
+        let (name, rest) = s.split_once(char::is_whitespace).ok_or(Error::NotEnoughFields)?;
+        let rest = rest.trim_start();
+        let (value, rest) = if let Some(q) = rest.strip_prefix('"') {
+            let end = q.find('"').ok_or(Error::NotEnoughFields)?;
+            (&rest[..end + 2], &q[end + 1..])
+        } else {
+            rest.split_once(char::is_whitespace).unwrap_or((rest, ""))
+        };
+        let duration = rest.trim();
+        Ok(Self {
+            name: name.to_owned(),
+            value: ParamValue::parse(value)?,
+            duration: if duration.is_empty() { None } else { Some(parse_duration(duration)?) },
+        })
+    }
+    pub fn encode(&self) -> String {
+        match self.duration {
+            Some(d) => format!("{} {} {}s", self.name, self.value, d),
+            None => format!("{} {}", self.name, self.value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BrushMode {
+    Reveal, // show the drawing background through the stroke
+    Ink, // paint with a palette color
+    Eraser,
+}
+impl std::str::FromStr for BrushMode {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "reveal" => Ok(Self::Reveal),
+            "ink" => Ok(Self::Ink),
+            "eraser" => Ok(Self::Eraser),
+            _ => Err(Error::BadField(s.to_owned())),
+        }
+    }
+}
+impl std::fmt::Display for BrushMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Reveal => write!(f, "reveal"),
+            Self::Ink => write!(f, "ink"),
+            Self::Eraser => write!(f, "eraser"),
+        }
+    }
+}
+
+/// Changes to the drawing brush; fields left out keep their current value.
+/// On the wire this is space-separated key=value pairs, e.g. "mode=ink color=3 size=2",
+/// where color indexes the drawing palette and size is the brush radius.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DrawingBrush {
+    pub mode: Option<BrushMode>,
+    pub color: Option<u8>,
+    pub size: Option<u8>,
+}
+impl DrawingBrush {
+    fn decode_field(&mut self, field: &str) -> Result<(), Error> {
+        match field.split_once('=') {
+            Some(("mode", v)) => self.mode = Some(v.parse()?),
+            Some(("color", v)) => self.color = Some(v.parse()?),
+            Some(("size", v)) => self.size = Some(v.parse()?),
+            _ => return Err(Error::BadField(field.to_owned())),
+        }
+        Ok(())
+    }
+    pub fn decode(s: &str) -> Result<Self, Error> {
+        let mut ret = Self::default();
+        for field in s.split_whitespace() { ret.decode_field(field)?; }
+        Ok(ret)
+    }
+    pub fn encode(&self) -> String {
+        let mut ret = Vec::new();
+        if let Some(m) = self.mode { ret.push(format!("mode={}", m)); }
+        if let Some(c) = self.color { ret.push(format!("color={}", c)); }
+        if let Some(s) = self.size { ret.push(format!("size={}", s)); }
+        ret.join(" ")
+    }
+}
+
+/// A polyline drawn onto the canvas in drawing coordinates, which span 1920x1080
+/// whatever the size of the screen. On the wire this is
+/// any brush fields followed by the points, e.g. "color=2 size=3 100,200 140,220".
+/// Brush fields left out use the overlay's current brush.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DrawingStroke {
+    pub brush: DrawingBrush,
+    pub points: Vec<(i32, i32)>,
+}
+impl DrawingStroke {
+    pub fn decode(s: &str) -> Result<Self, Error> {
+        let mut brush = DrawingBrush::default();
+        let mut points = Vec::new();
+        for field in s.split_whitespace() {
+            if let Some((x, y)) = field.split_once(',') {
+                points.push((x.parse()?, y.parse()?));
+            } else {
+                brush.decode_field(field)?;
+            }
+        }
+        if points.is_empty() { return Err(Error::NotEnoughFields) }
+        Ok(Self { brush, points })
+    }
+    pub fn encode(&self) -> String {
+        let mut ret = self.brush.encode();
+        for (x, y) in &self.points {
+            if !ret.is_empty() { ret.push(' '); }
+            ret.push_str(&format!("{},{}", x, y));
+        }
+        ret
+    }
+}
+
+/// Face tracking, with head rotation as Euler angles in degrees.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tracking {
+    pub eye_left: f32,
+    pub eye_right: f32,
+    pub mouth: f32,
+    pub euler_x: f32,
+    pub euler_y: f32,
+    pub euler_z: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackgroundFrame {
+    pub tag: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chat {
+    pub author: String,
+    pub msg: String,
+    pub time: f32,
+    pub biblicality: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpawnAnchor {
+    Emacs, // the Emacs cursor
+    Mouse,
+}
+impl std::str::FromStr for SpawnAnchor {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "emacs" => Ok(Self::Emacs),
+            "mouse" => Ok(Self::Mouse),
+            _ => Err(Error::BadField(s.to_owned())),
+        }
+    }
+}
+impl std::fmt::Display for SpawnAnchor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Emacs => write!(f, "emacs"),
+            Self::Mouse => write!(f, "mouse"),
+        }
+    }
+}
+
+/// Where and how to place a spawned pattern; the default drops it unturned somewhere random.
+/// On the wire this is space-separated key=value pairs, e.g. "anchor=emacs at=4,-2 rotate=90 flip=x".
+/// Patterns are centered on the anchor, offset by at cells if given; without an anchor,
+/// at is the cell for the pattern's top left corner. Flips happen before the clockwise rotation.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SpawnPlacement {
+    pub anchor: Option<SpawnAnchor>,
+    pub at: Option<(i32, i32)>,
+    pub rotate: u16, // degrees, one of 0, 90, 180 or 270
+    pub flip_x: bool, // mirror left to right
+    pub flip_y: bool, // mirror top to bottom
+}
+impl SpawnPlacement {
+    fn decode_field(&mut self, field: &str) -> Result<(), Error> {
+        let bad = || Error::BadField(field.to_owned());
+        match field.split_once('=') {
+            Some(("anchor", v)) => self.anchor = Some(v.parse()?),
+            Some(("at", v)) => {
+                let (x, y) = v.split_once(',').ok_or_else(bad)?;
+                self.at = Some((x.parse()?, y.parse()?));
+            },
+            Some(("rotate", v)) => {
+                let deg: u16 = v.parse()?;
+                if deg % 90 != 0 { return Err(bad()) }
+                self.rotate = deg % 360;
+            },
+            Some(("flip", v)) => {
+                if v.is_empty() || !v.chars().all(|c| c == 'x' || c == 'y') { return Err(bad()) }
+                self.flip_x = v.contains('x');
+                self.flip_y = v.contains('y');
+            },
+            _ => return Err(bad()),
+        }
+        Ok(())
+    }
+    pub fn decode(s: &str) -> Result<Self, Error> {
+        let mut ret = Self::default();
+        for field in s.split_whitespace() { ret.decode_field(field)?; }
+        Ok(ret)
+    }
+    pub fn encode(&self) -> String {
+        let mut ret = Vec::new();
+        if let Some(a) = self.anchor { ret.push(format!("anchor={}", a)); }
+        if let Some((x, y)) = self.at { ret.push(format!("at={},{}", x, y)); }
+        if self.rotate != 0 { ret.push(format!("rotate={}", self.rotate)); }
+        match (self.flip_x, self.flip_y) {
+            (true, true) => ret.push("flip=xy".to_owned()),
+            (true, false) => ret.push("flip=x".to_owned()),
+            (false, true) => ret.push("flip=y".to_owned()),
+            (false, false) => {},
+        }
+        ret.join(" ")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AutomataSpawn {
+    pub rle: String,
+    pub user: String,
+    pub color: u32,
+    pub placement: SpawnPlacement, // an optional trailing field, for older senders
+}
+
+/// A freshly minted trading card. On the wire this is a single
+/// tab-separated line, with colors written as "#rrggbb".
+#[derive(Debug, Clone, PartialEq)]
+pub struct TcgCard {
+    pub owner: String,
+    pub owner_id: String,
+    pub name: String,
+    pub ty: String,
+    pub depicted_subject: String,
+    pub element: String,
+    pub color: [u8; 3],
+    pub faction: String,
+    pub faction_color: [u8; 3],
+    pub equity: i64,
+    pub boost_level: String,
+    pub rarity: String,
+    pub rarity_level: i64,
+    pub body_text: String,
+    pub base_image_name: String,
+    pub set: String,
+    pub minted_date: String,
+    pub flags: String,
+}
+impl TcgCard {
+    fn parse_color(s: &str) -> Result<[u8; 3], Error> {
+        let hex = s.strip_prefix('#').filter(|h| h.len() == 6 && h.is_ascii())
+            .ok_or_else(|| Error::BadColor(s.to_owned()))?;
+        Ok([
+            u8::from_str_radix(&hex[0..2], 16)?,
+            u8::from_str_radix(&hex[2..4], 16)?,
+            u8::from_str_radix(&hex[4..6], 16)?,
+        ])
+    }
+    fn show_color(c: &[u8; 3]) -> String {
+        format!("#{:02x}{:02x}{:02x}", c[0], c[1], c[2])
+    }
+    pub fn decode(s: &str) -> Result<Self, Error> {
+        let mut sp = s.split('\t');
+        let mut next = || sp.next().ok_or(Error::NotEnoughFields);
+        Ok(Self {
+            owner: next()?.to_owned(),
+            owner_id: next()?.to_owned(),
+            name: next()?.to_owned(),
+            ty: next()?.to_owned(),
+            depicted_subject: next()?.to_owned(),
+            element: next()?.to_owned(),
+            color: Self::parse_color(next()?)?,
+            faction: next()?.to_owned(),
+            faction_color: Self::parse_color(next()?)?,
+            equity: next()?.parse()?,
+            boost_level: next()?.to_owned(),
+            rarity: next()?.to_owned(),
+            rarity_level: next()?.parse()?,
+            body_text: next()?.to_owned(),
+            base_image_name: next()?.to_owned(),
+            set: next()?.to_owned(),
+            minted_date: next()?.to_owned(),
+            flags: next()?.to_owned(),
+        })
+    }
+    pub fn encode(&self) -> String {
+        [
+            self.owner.clone(),
+            self.owner_id.clone(),
+            self.name.clone(),
+            self.ty.clone(),
+            self.depicted_subject.clone(),
+            self.element.clone(),
+            Self::show_color(&self.color),
+            self.faction.clone(),
+            Self::show_color(&self.faction_color),
+            self.equity.to_string(),
+            self.boost_level.clone(),
+            self.rarity.clone(),
+            self.rarity_level.to_string(),
+            self.body_text.clone(),
+            self.base_image_name.clone(),
+            self.set.clone(),
+            self.minted_date.clone(),
+            self.flags.clone(),
+        ].join("\t")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Reset,
+    /// Reset, but keep saved toggles and parameters.
+    ResetPreserve,
+    Tracking(Tracking),
+    BackgroundFrame(BackgroundFrame),
+    Toggle(ToggleChange),
+    ToggleSet(ToggleChange),
+    ToggleUnset(ToggleChange),
+    ToggleList,
+    /// Published by the renderer in reply to ToggleList: an s-expression alist of toggles.
+    ToggleStates(String),
+    ParamSet(ParamChange),
+    ParamUnset(String),
+    ParamList,
+    /// Published by the renderer in reply to ParamList: an s-expression alist of parameters.
+    ParamStates(String),
+    InfoEmacs { heartrate: i32 },
+    InfoEmacsCursor { x: f32, y: f32 },
+    InfoCreditsMusic(String),
+    InfoCreditsMusicClear,
+    Chat(Chat),
+    AvatarText(String),
+    Shader { author: String, shader: String },
+    ShaderChat(Vec<u8>),
+    AutomataSpawn(AutomataSpawn),
+    TcgGenerate(Box<TcgCard>),
+    DrawingBrush(DrawingBrush),
+    DrawingStroke(DrawingStroke),
+    DrawingClear,
+    DrawingUndo,
+    DrawingRedo,
+    /// Save the canvas as a PNG in Redis.
+    DrawingExport,
+    /// Stamp a saved drawing back onto the canvas, given its Redis key.
+    DrawingImport(String),
+    LayersSet(String),
+    LayersEnable(String),
+    LayersDisable(String),
+    /// Published by the renderer: an s-expression describing overlay health.
+    Status(String),
+    /// Published by the renderer: an alist of the users with the most live
+    /// automata cells, e.g. (("alice" . 120) ("bob" . 45)).
+    AutomataLeaderboard(String),
+}
+
+/// Every event name that decodes to a Message.
+pub const EVENTS: &[&[u8]] = &[
+    b"overlay reset",
+    b"overlay reset preserve",
+    b"overlay tracking",
+    b"overlay background frame",
+    b"overlay toggle",
+    b"overlay toggle set",
+    b"overlay toggle unset",
+    b"overlay toggle list",
+    b"overlay toggle states",
+    b"overlay param set",
+    b"overlay param unset",
+    b"overlay param list",
+    b"overlay param states",
+    b"overlay info emacs",
+    b"overlay info emacs cursor",
+    b"overlay info credits music",
+    b"overlay info credits music clear",
+    b"overlay chat",
+    b"overlay avatar text",
+    b"overlay shader",
+    b"overlay shader chat",
+    b"overlay automata spawn",
+    b"overlay tcg generate",
+    b"overlay drawing brush",
+    b"overlay drawing stroke",
+    b"overlay drawing clear",
+    b"overlay drawing undo",
+    b"overlay drawing redo",
+    b"overlay drawing export",
+    b"overlay drawing import",
+    b"overlay layers set",
+    b"overlay layers enable",
+    b"overlay layers disable",
+    b"overlay status",
+    b"overlay automata leaderboard",
+];
+
+impl Message {
+    pub fn event(&self) -> &'static [u8] {
+        match self {
+            Self::Reset => b"overlay reset",
+            Self::ResetPreserve => b"overlay reset preserve",
+            Self::Tracking(_) => b"overlay tracking",
+            Self::BackgroundFrame(_) => b"overlay background frame",
+            Self::Toggle(_) => b"overlay toggle",
+            Self::ToggleSet(_) => b"overlay toggle set",
+            Self::ToggleUnset(_) => b"overlay toggle unset",
+            Self::ToggleList => b"overlay toggle list",
+            Self::ToggleStates(_) => b"overlay toggle states",
+            Self::ParamSet(_) => b"overlay param set",
+            Self::ParamUnset(_) => b"overlay param unset",
+            Self::ParamList => b"overlay param list",
+            Self::ParamStates(_) => b"overlay param states",
+            Self::InfoEmacs { .. } => b"overlay info emacs",
+            Self::InfoEmacsCursor { .. } => b"overlay info emacs cursor",
+            Self::InfoCreditsMusic(_) => b"overlay info credits music",
+            Self::InfoCreditsMusicClear => b"overlay info credits music clear",
+            Self::Chat(_) => b"overlay chat",
+            Self::AvatarText(_) => b"overlay avatar text",
+            Self::Shader { .. } => b"overlay shader",
+            Self::ShaderChat(_) => b"overlay shader chat",
+            Self::AutomataSpawn(_) => b"overlay automata spawn",
+            Self::TcgGenerate(_) => b"overlay tcg generate",
+            Self::DrawingBrush(_) => b"overlay drawing brush",
+            Self::DrawingStroke(_) => b"overlay drawing stroke",
+            Self::DrawingClear => b"overlay drawing clear",
+            Self::DrawingUndo => b"overlay drawing undo",
+            Self::DrawingRedo => b"overlay drawing redo",
+            Self::DrawingExport => b"overlay drawing export",
+            Self::DrawingImport(_) => b"overlay drawing import",
+            Self::LayersSet(_) => b"overlay layers set",
+            Self::LayersEnable(_) => b"overlay layers enable",
+            Self::LayersDisable(_) => b"overlay layers disable",
+            Self::Status(_) => b"overlay status",
+            Self::AutomataLeaderboard(_) => b"overlay automata leaderboard",
+        }
+    }
+
+    pub fn decode(event: &[u8], data: &[u8]) -> Result<Self, Error> {
+        let mut r = std::io::Cursor::new(data);
+        Ok(match event {
+            b"overlay reset" => Self::Reset,
+            b"overlay reset preserve" => Self::ResetPreserve,
+            b"overlay tracking" => Self::Tracking(Tracking {
+                eye_left: r.read_f32::<LE>()?,
+                eye_right: r.read_f32::<LE>()?,
+                mouth: r.read_f32::<LE>()?,
+                euler_x: r.read_f32::<LE>()?,
+                euler_y: r.read_f32::<LE>()?,
+                euler_z: r.read_f32::<LE>()?,
+            }),
+            b"overlay background frame" => {
+                let tag = read_prefixed(&mut r)?;
+                let width = r.read_u32::<LE>()?;
+                let height = r.read_u32::<LE>()?;
+                let mut pixels = Vec::new();
+                r.read_to_end(&mut pixels)?;
+                Self::BackgroundFrame(BackgroundFrame { tag, width, height, pixels })
+            },
+            b"overlay toggle" => Self::Toggle(ToggleChange::decode(&utf8(data)?)?),
+            b"overlay toggle set" => Self::ToggleSet(ToggleChange::decode(&utf8(data)?)?),
+            b"overlay toggle unset" => Self::ToggleUnset(ToggleChange::decode(&utf8(data)?)?),
+            b"overlay toggle list" => Self::ToggleList,
+            b"overlay toggle states" => Self::ToggleStates(utf8(data)?),
+            b"overlay param set" => Self::ParamSet(ParamChange::decode(&utf8(data)?)?),
+            b"overlay param unset" => Self::ParamUnset(utf8(data)?),
+            b"overlay param list" => Self::ParamList,
+            b"overlay param states" => Self::ParamStates(utf8(data)?),
+            b"overlay info emacs" => Self::InfoEmacs { heartrate: r.read_i32::<LE>()? },
+            b"overlay info emacs cursor" => Self::InfoEmacsCursor {
+                x: read_prefixed_utf8(&mut r)?.parse()?,
+                y: read_prefixed_utf8(&mut r)?.parse()?,
+            },
+            b"overlay info credits music" => Self::InfoCreditsMusic(utf8(data)?),
+            b"overlay info credits music clear" => Self::InfoCreditsMusicClear,
+            b"overlay chat" => Self::Chat(Chat {
+                author: read_prefixed_utf8(&mut r)?,
+                msg: read_prefixed_utf8(&mut r)?,
+                time: read_prefixed_utf8(&mut r)?.parse()?,
+                biblicality: read_prefixed_utf8(&mut r)?.parse()?,
+            }),
+            b"overlay avatar text" => Self::AvatarText(utf8(data)?),
+            b"overlay shader" => Self::Shader {
+                author: read_prefixed_utf8(&mut r)?,
+                shader: read_prefixed_utf8(&mut r)?,
+            },
+            b"overlay shader chat" => Self::ShaderChat(data.to_vec()),
+            b"overlay automata spawn" => Self::AutomataSpawn(AutomataSpawn {
+                rle: read_prefixed_utf8(&mut r)?,
+                user: read_prefixed_utf8(&mut r)?,
+                color: r.read_u32::<LE>()?,
+                placement: if (r.position() as usize) < data.len() {
+                    SpawnPlacement::decode(&read_prefixed_utf8(&mut r)?)?
+                } else {
+                    SpawnPlacement::default()
+                },
+            }),
+            b"overlay tcg generate" => Self::TcgGenerate(Box::new(TcgCard::decode(&utf8(data)?)?)),
+            b"overlay drawing brush" => Self::DrawingBrush(DrawingBrush::decode(&utf8(data)?)?),
+            b"overlay drawing stroke" => Self::DrawingStroke(DrawingStroke::decode(&utf8(data)?)?),
+            b"overlay drawing clear" => Self::DrawingClear,
+            b"overlay drawing undo" => Self::DrawingUndo,
+            b"overlay drawing redo" => Self::DrawingRedo,
+            b"overlay drawing export" => Self::DrawingExport,
+            b"overlay drawing import" => Self::DrawingImport(utf8(data)?),
+            b"overlay layers set" => Self::LayersSet(utf8(data)?),
+            b"overlay layers enable" => Self::LayersEnable(utf8(data)?),
+            b"overlay layers disable" => Self::LayersDisable(utf8(data)?),
+            b"overlay status" => Self::Status(utf8(data)?),
+            b"overlay automata leaderboard" => Self::AutomataLeaderboard(utf8(data)?),
+            _ => return Err(Error::UnknownEvent(String::from_utf8_lossy(event).into_owned())),
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Vec::new();
+        match self {
+            Self::Reset | Self::ResetPreserve | Self::InfoCreditsMusicClear | Self::ToggleList | Self::ParamList
+                | Self::DrawingClear | Self::DrawingUndo | Self::DrawingRedo | Self::DrawingExport => {},
+            Self::Tracking(t) => {
+                for x in [t.eye_left, t.eye_right, t.mouth, t.euler_x, t.euler_y, t.euler_z] {
+                    w.write_f32::<LE>(x).expect("write to Vec failed");
+                }
+            },
+            Self::BackgroundFrame(f) => {
+                write_prefixed(&mut w, &f.tag);
+                w.write_u32::<LE>(f.width).expect("write to Vec failed");
+                w.write_u32::<LE>(f.height).expect("write to Vec failed");
+                w.extend_from_slice(&f.pixels);
+            },
+            Self::Toggle(t) | Self::ToggleSet(t) | Self::ToggleUnset(t)
+                => w.extend_from_slice(t.encode().as_bytes()),
+            Self::InfoCreditsMusic(s) | Self::AvatarText(s)
+                | Self::LayersSet(s) | Self::LayersEnable(s) | Self::LayersDisable(s)
+                | Self::Status(s) | Self::ToggleStates(s) | Self::ParamUnset(s) | Self::ParamStates(s)
+                | Self::DrawingImport(s) | Self::AutomataLeaderboard(s)
+                => w.extend_from_slice(s.as_bytes()),
+            Self::InfoEmacs { heartrate } => w.write_i32::<LE>(*heartrate).expect("write to Vec failed"),
+            Self::InfoEmacsCursor { x, y } => {
+                write_prefixed(&mut w, x.to_string().as_bytes());
+                write_prefixed(&mut w, y.to_string().as_bytes());
+            },
+            Self::Chat(c) => {
+                write_prefixed(&mut w, c.author.as_bytes());
+                write_prefixed(&mut w, c.msg.as_bytes());
+                write_prefixed(&mut w, c.time.to_string().as_bytes());
+                write_prefixed(&mut w, c.biblicality.to_string().as_bytes());
+            },
+            Self::Shader { author, shader } => {
+                write_prefixed(&mut w, author.as_bytes());
+                write_prefixed(&mut w, shader.as_bytes());
+            },
+            Self::ShaderChat(d) => w.extend_from_slice(d),
+            Self::AutomataSpawn(s) => {
+                write_prefixed(&mut w, s.rle.as_bytes());
+                write_prefixed(&mut w, s.user.as_bytes());
+                w.write_u32::<LE>(s.color).expect("write to Vec failed");
+                if s.placement != SpawnPlacement::default() {
+                    write_prefixed(&mut w, s.placement.encode().as_bytes());
+                }
+            },
+            Self::TcgGenerate(c) => w.extend_from_slice(c.encode().as_bytes()),
+            Self::ParamSet(p) => w.extend_from_slice(p.encode().as_bytes()),
+            Self::DrawingBrush(b) => w.extend_from_slice(b.encode().as_bytes()),
+            Self::DrawingStroke(s) => w.extend_from_slice(s.encode().as_bytes()),
+        }
+        w
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn card() -> TcgCard {
+        TcgCard {
+            owner: "alice".to_owned(),
+            owner_id: "1234".to_owned(),
+            name: "Hexagon Sage".to_owned(),
+            ty: "creature".to_owned(),
+            depicted_subject: "a wizard made of hexagons".to_owned(),
+            element: "fire".to_owned(),
+            color: [0x12, 0xab, 0xff],
+            faction: "bees".to_owned(),
+            faction_color: [0x00, 0x80, 0x0f],
+            equity: 3,
+            boost_level: "none".to_owned(),
+            rarity: "rare".to_owned(),
+            rarity_level: 2,
+            body_text: "When this enters, draw a card.".to_owned(),
+            base_image_name: "sage.png".to_owned(),
+            set: "alpha".to_owned(),
+            minted_date: "2024-01-01".to_owned(),
+            flags: "".to_owned(),
+        }
+    }
+
+    /// One or more of every variant, exercising the optional and quoted fields.
+    fn samples() -> Vec<Message> {
+        vec![
+            Message::Reset,
+            Message::ResetPreserve,
+            Message::Tracking(Tracking { eye_left: 0.5, eye_right: 1.0, mouth: 0.25, euler_x: -10.0, euler_y: 3.5, euler_z: 0.0 }),
+            Message::BackgroundFrame(BackgroundFrame { tag: b"drawing".to_vec(), width: 2, height: 1, pixels: vec![1, 2, 3, 4, 5, 6, 7, 8] }),
+            Message::Toggle(ToggleChange { name: "adblock".to_owned(), duration: None }),
+            Message::ToggleSet(ToggleChange { name: "adblock".to_owned(), duration: Some(1.5) }),
+            Message::ToggleUnset(ToggleChange { name: "adblock".to_owned(), duration: Some(30.0) }),
+            Message::ToggleList,
+            Message::ToggleStates("((adblock . t))".to_owned()),
+            Message::ParamSet(ParamChange { name: "shader_opacity".to_owned(), value: ParamValue::Float(0.8), duration: Some(2.0) }),
+            Message::ParamSet(ParamChange { name: "shader_opacity".to_owned(), value: ParamValue::Float(1.0), duration: None }),
+            Message::ParamSet(ParamChange { name: "automata_step_ticks".to_owned(), value: ParamValue::Int(-3), duration: None }),
+            Message::ParamSet(ParamChange { name: "tint".to_owned(), value: ParamValue::Color([1, 2, 3, 4]), duration: Some(0.5) }),
+            Message::ParamSet(ParamChange { name: "title".to_owned(), value: ParamValue::String("hello there world".to_owned()), duration: Some(2.0) }),
+            Message::ParamSet(ParamChange { name: "title".to_owned(), value: ParamValue::String("  padded  ".to_owned()), duration: None }),
+            Message::ParamUnset("shader_opacity".to_owned()),
+            Message::ParamList,
+            Message::ParamStates("((shader_opacity . 0.8))".to_owned()),
+            Message::InfoEmacs { heartrate: 72 },
+            Message::InfoEmacsCursor { x: 120.5, y: -4.0 },
+            Message::InfoCreditsMusic("someone - something".to_owned()),
+            Message::InfoCreditsMusicClear,
+            Message::Chat(Chat { author: "bob".to_owned(), msg: "hi there".to_owned(), time: 12.25, biblicality: 0.75 }),
+            Message::AvatarText("hello".to_owned()),
+            Message::Shader { author: "carol".to_owned(), shader: "void main() {}".to_owned() },
+            Message::ShaderChat(vec![0, 1, 2, 0xff]),
+            Message::AutomataSpawn(AutomataSpawn {
+                rle: "x = 3, y = 1\n3o!".to_owned(),
+                user: "dave".to_owned(),
+                color: 0xff8000,
+                placement: SpawnPlacement::default(),
+            }),
+            Message::AutomataSpawn(AutomataSpawn {
+                rle: "x = 3, y = 1\n3o!".to_owned(),
+                user: "dave".to_owned(),
+                color: 0x00ff00,
+                placement: SpawnPlacement { anchor: Some(SpawnAnchor::Emacs), at: Some((4, -2)), rotate: 90, flip_x: true, flip_y: false },
+            }),
+            Message::AutomataSpawn(AutomataSpawn {
+                rle: "bo$2bo$3o!".to_owned(),
+                user: "".to_owned(),
+                color: 0,
+                placement: SpawnPlacement { anchor: None, at: Some((10, 20)), rotate: 270, flip_x: true, flip_y: true },
+            }),
+            Message::TcgGenerate(Box::new(card())),
+            Message::DrawingBrush(DrawingBrush { mode: Some(BrushMode::Ink), color: Some(3), size: None }),
+            Message::DrawingBrush(DrawingBrush::default()),
+            Message::DrawingStroke(DrawingStroke { brush: DrawingBrush::default(), points: vec![(100, 200), (-5, 7)] }),
+            Message::DrawingStroke(DrawingStroke {
+                brush: DrawingBrush { mode: Some(BrushMode::Eraser), color: Some(2), size: Some(4) },
+                points: vec![(0, 0)],
+            }),
+            Message::DrawingClear,
+            Message::DrawingUndo,
+            Message::DrawingRedo,
+            Message::DrawingExport,
+            Message::DrawingImport("drawing:snapshot:1700000000000".to_owned()),
+            Message::LayersSet("automata,drawing".to_owned()),
+            Message::LayersEnable("tcg".to_owned()),
+            Message::LayersDisable("model".to_owned()),
+            Message::Status("((tick . 60))".to_owned()),
+            Message::AutomataLeaderboard("((\"alice\" . 120) (\"bob\" . 45))".to_owned()),
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        for msg in samples() {
+            let decoded = Message::decode(msg.event(), &msg.encode())
+                .unwrap_or_else(|e| panic!("failed to decode {:?}: {}", msg, e));
+            assert_eq!(decoded, msg);
+        }
+    }
+
+    #[test]
+    fn events_match_variants() {
+        let samples = samples();
+        for ev in EVENTS {
+            assert!(samples.iter().any(|m| m.event() == *ev), "no variant for {}", String::from_utf8_lossy(ev));
+        }
+        for m in &samples {
+            assert!(EVENTS.contains(&m.event()), "{} missing from EVENTS", String::from_utf8_lossy(m.event()));
+        }
+        for (i, ev) in EVENTS.iter().enumerate() {
+            assert!(!EVENTS[i + 1..].contains(ev), "{} listed twice", String::from_utf8_lossy(ev));
+        }
+    }
+
+    #[test]
+    fn spawn_without_placement() {
+        // older senders stop after the color
+        let mut data = Vec::new();
+        write_prefixed(&mut data, b"3o!");
+        write_prefixed(&mut data, b"erin");
+        data.write_u32::<LE>(0xff).unwrap();
+        match Message::decode(b"overlay automata spawn", &data).unwrap() {
+            Message::AutomataSpawn(s) => assert_eq!(s.placement, SpawnPlacement::default()),
+            m => panic!("decoded to {:?}", m),
+        }
+    }
+
+    #[test]
+    fn toggle_durations() {
+        assert_eq!(ToggleChange::decode("adblock").unwrap().duration, None);
+        assert_eq!(ToggleChange::decode("adblock 500ms").unwrap().duration, Some(0.5));
+        assert_eq!(ToggleChange::decode("adblock 2m").unwrap().duration, Some(120.0));
+        assert!(ToggleChange::decode("adblock -1s").is_err());
+    }
+
+    #[test]
+    fn tcg_colors() {
+        let c = card();
+        assert!(c.encode().contains("#12abff\tbees\t#00800f"));
+        let mut bad = c.encode().replace("#12abff", "12abff");
+        assert!(TcgCard::decode(&bad).is_err());
+        bad = c.encode().replace("#12abff", "#12abzz");
+        assert!(TcgCard::decode(&bad).is_err());
+    }
+
+    #[test]
+    fn unknown_event() {
+        assert!(Message::decode(b"overlay nonsense", b"").is_err());
+    }
+}
@@ -1,10 +1,20 @@
 uniform sampler2D texture_data;
-uniform sampler2D background;
+uniform sampler2D palette;
+uniform sampler2D rules;
 
 void main()
 {
     vec2 tcfull = vec2(vertex_texcoord.x, 1.0 - vertex_texcoord.y);
-    vec4 texel = texture(texture_data, tcfull);
+    // owner in red and age in green, as bytes
+    vec2 cell = floor(texture(texture_data, tcfull).rg * 255.0 + 0.5);
+    if (cell.x < 0.5) {
+        discard;
+    }
+    ivec2 entry = ivec2(int(cell.x), 0);
+    vec4 texel = texelFetch(palette, entry, 0);
+    // dying cells fade out over the states left to them
+    float states = floor(texelFetch(rules, entry, 0).a * 255.0 + 0.5);
+    texel.a *= clamp(1.0 - cell.y / max(states - 1.0, 1.0), 0.0, 1.0);
     texel.a /= 1.15;
     frag_color = texel;
 }
@@ -0,0 +1,66 @@
+uniform sampler2D texture_data;
+uniform sampler2D rules;
+
+// owner and age of the cell at p, wrapping around the edges
+ivec2 cell(ivec2 p)
+{
+    ivec2 size = textureSize(texture_data, 0);
+    return ivec2(floor(texelFetch(texture_data, (p + size) % size, 0).rg * 255.0 + 0.5));
+}
+
+// birth counts 0-7 in red, survival counts 0-7 in green, both counts of 8 in blue, states in alpha
+ivec4 rule(int owner)
+{
+    return ivec4(floor(texelFetch(rules, ivec2(owner, 0), 0) * 255.0 + 0.5));
+}
+
+bool births(ivec4 r, int n)
+{
+    return n == 8 ? (r.b & 1) != 0 : ((r.r >> n) & 1) != 0;
+}
+
+bool survives(ivec4 r, int n)
+{
+    return n == 8 ? (r.b & 2) != 0 : ((r.g >> n) & 1) != 0;
+}
+
+void main()
+{
+    ivec2 p = ivec2(gl_FragCoord.xy);
+    ivec2 c = cell(p);
+    int owners[8];
+    int n = 0;
+    for (int dy = -1; dy <= 1; dy++) {
+        for (int dx = -1; dx <= 1; dx++) {
+            if (dx == 0 && dy == 0) continue;
+            ivec2 q = cell(p + ivec2(dx, dy));
+            if (q.x > 0 && q.y == 0) {
+                owners[n] = q.x;
+                n++;
+            }
+        }
+    }
+    // the most common live neighbor's owner, ties going to the higher index as on the CPU
+    int winner = 0;
+    int score = 0;
+    for (int i = 0; i < n; i++) {
+        int k = 0;
+        for (int j = 0; j < n; j++) {
+            if (owners[j] == owners[i]) k++;
+        }
+        if (k > score || (k == score && owners[i] > winner)) {
+            winner = owners[i];
+            score = k;
+        }
+    }
+    ivec2 next = c;
+    if (c.x > 0 && c.y > 0) {
+        int age = c.y + 1;
+        next = age + 1 >= rule(c.x).a ? ivec2(0) : ivec2(c.x, age);
+    } else if (c.x > 0 && !survives(rule(c.x), n)) {
+        next = rule(c.x).a > 2 ? ivec2(c.x, 1) : ivec2(0);
+    } else if (winner > 0 && births(rule(winner), n)) {
+        next = ivec2(winner, 0);
+    }
+    frag_color = vec4(vec2(next) / 255.0, 0.0, 1.0);
+}
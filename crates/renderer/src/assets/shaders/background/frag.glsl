@@ -1,13 +1,21 @@
 uniform sampler2D texture_data;
 uniform sampler2D background;
+uniform sampler2D palette;
 
 void main()
 {
     vec2 tcfull = vec2(vertex_texcoord.x, 1.0 - vertex_texcoord.y);
-    vec4 texel = texture(texture_data, tcfull);
-    if (texel.r == 0.0) {
+    vec2 texel = texture(texture_data, tcfull).rg;
+    float idx = texel.r * 255.0;
+    float coverage = texel.g;
+    if (idx < 0.5 || coverage == 0.0) {
         discard;
     }
-    // frag_color = vec4(1.0, 1.0, 1.0, 1.0);
-    frag_color = vec4(texture(background, tcfull).xyz, 1.0);
+    vec4 ink = texture(palette, vec2((floor(idx + 0.5) + 0.5) / 256.0, 0.5));
+    // transparent palette entries reveal the background instead
+    if (ink.a == 0.0) {
+        frag_color = vec4(texture(background, tcfull).xyz, coverage);
+    } else {
+        frag_color = vec4(ink.rgb, ink.a * coverage);
+    }
 }
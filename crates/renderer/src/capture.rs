@@ -0,0 +1,70 @@
+use teleia::*;
+
+use crate::overlay;
+
+/// Runs an overlay stack and writes composited frames to disk as PNG.
+/// Stops after the frame limit, or when the replay runs out if there is no limit.
+pub struct Capture {
+    overlays: Option<overlay::Overlays>, // None once capture is done
+    dir: std::path::PathBuf,
+    every: u64,
+    limit: Option<u64>,
+    frame: u64,
+}
+impl Capture {
+    pub fn new(overlays: overlay::Overlays, dir: &str, every: u64, limit: Option<u64>) -> Self {
+        Self {
+            overlays: Some(overlays),
+            dir: std::path::PathBuf::from(dir),
+            every: every.max(1),
+            limit,
+            frame: 0,
+        }
+    }
+    fn save(&self, ctx: &context::Context, st: &mut state::State) -> Erm<()> {
+        let w = st.render_dims.x as u32;
+        let h = st.render_dims.y as u32;
+        let mut pixels = vec![0; (w * h * 4) as usize];
+        st.render_framebuffer.get_pixels_raw(ctx, &mut pixels);
+        let mut img = image::RgbaImage::from_raw(w, h, pixels).ok_or("frame size mismatch")?;
+        image::imageops::flip_vertical_in_place(&mut img);
+        img.save(self.dir.join(format!("frame{:06}.png", self.frame)))?;
+        Ok(())
+    }
+    /// Exit once the last frame is written. The overlays are dropped first so their
+    /// destructors run (removing the status socket, for one) and output is flushed,
+    /// as returning from main would do.
+    fn finish(&mut self) -> ! {
+        self.overlays = None;
+        log::logger().flush();
+        let _ = std::io::Write::flush(&mut std::io::stdout());
+        std::process::exit(0)
+    }
+}
+impl teleia::state::Game for Capture {
+    fn update(&mut self, ctx: &context::Context, st: &mut state::State) -> Erm<()> {
+        match &mut self.overlays {
+            Some(o) => state::Game::update(o, ctx, st),
+            None => Ok(()),
+        }
+    }
+    fn render(&mut self, ctx: &context::Context, st: &mut state::State) -> Erm<()> {
+        let Some(overlays) = &mut self.overlays else { return Ok(()) };
+        state::Game::render(overlays, ctx, st)?;
+        let replay_finished = overlays.replay_finished();
+        if self.frame % self.every == 0 {
+            std::fs::create_dir_all(&self.dir)?;
+            self.save(ctx, st)?;
+        }
+        self.frame += 1;
+        let done = match self.limit {
+            Some(l) => self.frame >= l,
+            None => replay_finished,
+        };
+        if done {
+            log::info!("captured {} frames to {}", self.frame.div_ceil(self.every), self.dir.display());
+            self.finish();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,224 @@
+use teleia::*;
+
+use crate::input;
+
+pub const DEFAULT_BUS: &str = "shiro:32051";
+pub const DEFAULT_REDIS: &str = "redis://shiro";
+pub const DEFAULT_SPOOL: &str = ".spool";
+pub const DEFAULT_STATUS_INTERVAL: f32 = 5.0;
+pub const DEFAULT_STATE_FILE: &str = ".overlay-state";
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    ReadFailed(String, String),
+    ParseFailed(String, String),
+    UnknownKey(String),
+    BadValue(String),
+    ConnectFailed(&'static str, String, String),
+}
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ReadFailed(path, e) => write!(f, "failed to read config file {}: {}", path, e),
+            Self::ParseFailed(path, e) => write!(f, "failed to parse config file {}: {}", path, e),
+            Self::UnknownKey(k) => write!(f, "unknown config key: {}", k),
+            Self::BadValue(k) => write!(f, "bad value for config key: {}", k),
+            Self::ConnectFailed(service, addr, e) => write!(f, "failed to connect to {} at {}: {}", service, addr, e),
+        }
+    }
+}
+impl std::error::Error for Error {}
+
+/// The value of an environment variable, or None if it is unset.
+/// A variable that is set but does not parse is an error, as in the config file.
+fn env_parse<T: std::str::FromStr>(name: &str) -> Result<Option<T>, Error> {
+    match std::env::var(name) {
+        Ok(v) => v.trim().parse().map(Some).map_err(|_| Error::BadValue(name.to_owned())),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(_) => Err(Error::BadValue(name.to_owned())),
+    }
+}
+
+/// Runtime settings for the renderer.
+/// Later sources override earlier ones: defaults, then the config file,
+/// then environment variables, then command line flags.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bus: String,
+    pub redis: String,
+    pub spool: String,
+    pub fault_limit: Option<u32>,
+    pub status_interval: f32,
+    pub status_socket: Option<String>,
+    pub state_file: String,
+    pub bindings: Vec<input::Binding>,
+    pub drawing_region: Option<input::Region>,
+    pub automata_cpu: bool,
+    pub automata_scale: Option<usize>, // screen pixels per cell, defaulting by backend
+}
+impl Config {
+    pub fn new() -> Self {
+        Self {
+            bus: DEFAULT_BUS.to_owned(),
+            redis: DEFAULT_REDIS.to_owned(),
+            spool: DEFAULT_SPOOL.to_owned(),
+            fault_limit: None,
+            status_interval: DEFAULT_STATUS_INTERVAL,
+            status_socket: None,
+            state_file: DEFAULT_STATE_FILE.to_owned(),
+            bindings: input::default_bindings(),
+            drawing_region: None,
+            automata_cpu: false,
+            automata_scale: None,
+        }
+    }
+
+    /// Flags shared by every subcommand.
+    pub fn args() -> Vec<clap::Arg> {
+        vec![
+            clap::Arg::new("config")
+                .long("config")
+                .value_name("PATH")
+                .help("Read settings from an s-expression config file (env: NEWTON_CONFIG)")
+                .global(true),
+            clap::Arg::new("bus")
+                .long("bus")
+                .value_name("HOST:PORT")
+                .help("Address of the fig message bus (env: NEWTON_BUS)")
+                .global(true),
+            clap::Arg::new("redis")
+                .long("redis")
+                .value_name("URL")
+                .help("Redis connection URL (env: NEWTON_REDIS)")
+                .global(true),
+            clap::Arg::new("spool")
+                .long("spool")
+                .value_name("DIR")
+                .help("Directory holding Redis writes made while Redis is unreachable (env: NEWTON_SPOOL)")
+                .global(true),
+            clap::Arg::new("fault-limit")
+                .long("fault-limit")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u32))
+                .help("Disable an overlay after N consecutive failing frames (env: NEWTON_FAULT_LIMIT)")
+                .global(true),
+            clap::Arg::new("status-interval")
+                .long("status-interval")
+                .value_name("SECONDS")
+                .value_parser(clap::value_parser!(f32))
+                .help("Publish overlay status on the bus this often, or never if 0 (env: NEWTON_STATUS_INTERVAL)")
+                .global(true),
+            clap::Arg::new("status-socket")
+                .long("status-socket")
+                .value_name("PATH")
+                .help("Also serve overlay status on a Unix socket (env: NEWTON_STATUS_SOCKET)")
+                .global(true),
+            clap::Arg::new("state-file")
+                .long("state-file")
+                .value_name("PATH")
+                .help("Where to save toggles and parameters while Redis is unavailable (env: NEWTON_STATE_FILE)")
+                .global(true),
+            clap::Arg::new("drawing-region")
+                .long("drawing-region")
+                .value_name("X,Y,WIDTH,HEIGHT")
+                .help("Part of the desktop the overlay covers, in mouse coordinates (env: NEWTON_DRAWING_REGION)")
+                .global(true),
+            clap::Arg::new("automata-cpu")
+                .long("automata-cpu")
+                .action(clap::ArgAction::SetTrue)
+                .help("Step the automata on the CPU instead of in a shader (env: NEWTON_AUTOMATA_CPU)")
+                .global(true),
+            clap::Arg::new("automata-scale")
+                .long("automata-scale")
+                .value_name("PIXELS")
+                .value_parser(clap::value_parser!(usize))
+                .help("Screen pixels per automata cell, 4 by default or 15 on the CPU (env: NEWTON_AUTOMATA_SCALE)")
+                .global(true),
+        ]
+    }
+
+    pub fn from_matches(m: &clap::ArgMatches) -> Erm<Self> {
+        let mut ret = Self::new();
+        let path = m.get_one::<String>("config").cloned()
+            .or_else(|| std::env::var("NEWTON_CONFIG").ok());
+        if let Some(p) = path { ret.load_file(&p)?; }
+        ret.load_env()?;
+        ret.load_matches(m)?;
+        Ok(ret)
+    }
+
+    /// The config file is an association list, e.g.
+    /// ((bus . "localhost:32051") (redis . "redis://localhost")
+    ///  (bindings ("LMeta" . draw) ("LControl+Z" . undo) ("F9" . (layer "automata")))
+    ///  (drawing-region 1920 0 2560 1440) (automata-scale . 1))
+    pub fn load_file(&mut self, path: &str) -> Erm<()> {
+        let src = std::fs::read_to_string(path)
+            .map_err(|e| Error::ReadFailed(path.to_owned(), e.to_string()))?;
+        let v = lexpr::from_str(&src)
+            .map_err(|e| Error::ParseFailed(path.to_owned(), e.to_string()))?;
+        let entries = v.list_iter()
+            .ok_or_else(|| Error::ParseFailed(path.to_owned(), "expected a list".to_owned()))?;
+        for ent in entries {
+            let c = ent.as_cons()
+                .ok_or_else(|| Error::ParseFailed(path.to_owned(), format!("expected a pair, got {}", ent)))?;
+            let key = c.car().as_symbol()
+                .ok_or_else(|| Error::ParseFailed(path.to_owned(), format!("expected a symbol, got {}", c.car())))?;
+            self.set(key, c.cdr())?;
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, val: &lexpr::Value) -> Erm<()> {
+        let string = || val.as_str().map(|s| s.to_owned()).ok_or_else(|| Error::BadValue(key.to_owned()));
+        match key {
+            "bus" => self.bus = string()?,
+            "redis" => self.redis = string()?,
+            "spool" => self.spool = string()?,
+            "fault-limit" => self.fault_limit = Some(
+                val.as_u64().and_then(|n| n.try_into().ok()).ok_or_else(|| Error::BadValue(key.to_owned()))?
+            ),
+            "status-interval" => self.status_interval =
+                val.as_f64().ok_or_else(|| Error::BadValue(key.to_owned()))? as f32,
+            "status-socket" => self.status_socket = Some(string()?),
+            "state-file" => self.state_file = string()?,
+            // replaces the default bindings entirely
+            "bindings" => self.bindings = input::Binding::parse_all(val)?,
+            "drawing-region" => self.drawing_region = Some(input::Region::from_sexp(val)?),
+            "automata-cpu" => self.automata_cpu =
+                val.as_bool().ok_or_else(|| Error::BadValue(key.to_owned()))?,
+            "automata-scale" => self.automata_scale = Some(
+                val.as_u64().and_then(|n| n.try_into().ok()).ok_or_else(|| Error::BadValue(key.to_owned()))?
+            ),
+            _ => return Err(Error::UnknownKey(key.to_owned()).into()),
+        }
+        Ok(())
+    }
+
+    pub fn load_env(&mut self) -> Erm<()> {
+        if let Ok(v) = std::env::var("NEWTON_BUS") { self.bus = v; }
+        if let Ok(v) = std::env::var("NEWTON_REDIS") { self.redis = v; }
+        if let Ok(v) = std::env::var("NEWTON_SPOOL") { self.spool = v; }
+        if let Some(n) = env_parse("NEWTON_FAULT_LIMIT")? { self.fault_limit = Some(n); }
+        if let Some(n) = env_parse("NEWTON_STATUS_INTERVAL")? { self.status_interval = n; }
+        if let Ok(v) = std::env::var("NEWTON_STATUS_SOCKET") { self.status_socket = Some(v); }
+        if let Ok(v) = std::env::var("NEWTON_STATE_FILE") { self.state_file = v; }
+        if let Ok(v) = std::env::var("NEWTON_DRAWING_REGION") { self.drawing_region = Some(input::Region::parse(&v)?); }
+        if let Ok(v) = std::env::var("NEWTON_AUTOMATA_CPU") { self.automata_cpu = !v.is_empty() && v != "0"; }
+        if let Some(n) = env_parse("NEWTON_AUTOMATA_SCALE")? { self.automata_scale = Some(n); }
+        Ok(())
+    }
+
+    pub fn load_matches(&mut self, m: &clap::ArgMatches) -> Erm<()> {
+        if let Some(v) = m.get_one::<String>("bus") { self.bus = v.clone(); }
+        if let Some(v) = m.get_one::<String>("redis") { self.redis = v.clone(); }
+        if let Some(v) = m.get_one::<String>("spool") { self.spool = v.clone(); }
+        if let Some(v) = m.get_one::<u32>("fault-limit") { self.fault_limit = Some(*v); }
+        if let Some(v) = m.get_one::<f32>("status-interval") { self.status_interval = *v; }
+        if let Some(v) = m.get_one::<String>("status-socket") { self.status_socket = Some(v.clone()); }
+        if let Some(v) = m.get_one::<String>("state-file") { self.state_file = v.clone(); }
+        if let Some(v) = m.get_one::<String>("drawing-region") { self.drawing_region = Some(input::Region::parse(v)?); }
+        if m.get_flag("automata-cpu") { self.automata_cpu = true; }
+        if let Some(v) = m.get_one::<usize>("automata-scale") { self.automata_scale = Some(*v); }
+        Ok(())
+    }
+}
@@ -1,30 +1,210 @@
-use device_query::DeviceQuery;
+use device_query::{DeviceQuery, Keycode};
+use newton_messages::BrushMode;
 
+use crate::overlay;
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    BadKey(String),
+    BadCommand(String),
+    BadRegion(String),
+}
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadKey(k) => write!(f, "unknown key: {}", k),
+            Self::BadCommand(c) => write!(f, "bad command: {}", c),
+            Self::BadRegion(r) => write!(f, "bad screen region: {}", r),
+        }
+    }
+}
+impl std::error::Error for Error {}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Command {
-    None,
-    Drawing,
+    Drawing, // active while held
     EraseAll,
+    CycleColor,
+    CycleSize,
+    Brush(BrushMode),
+    Undo,
+    Redo,
+    Export,
+    ToggleLayer(&'static str),
+    Publish(String, String), // event and data
+}
+impl Command {
+    /// Commands are written as a symbol, or a list for those taking arguments:
+    /// draw, erase-all, cycle-color, cycle-size, reveal, ink, eraser, undo, redo, export,
+    /// (layer "automata"), (publish "overlay toggle" "adblock")
+    pub fn parse(v: &lexpr::Value) -> Result<Self, Error> {
+        let bad = || Error::BadCommand(v.to_string());
+        if let Some(s) = v.as_symbol() {
+            return match s {
+                "draw" => Ok(Self::Drawing),
+                "erase-all" => Ok(Self::EraseAll),
+                "cycle-color" => Ok(Self::CycleColor),
+                "cycle-size" => Ok(Self::CycleSize),
+                "reveal" => Ok(Self::Brush(BrushMode::Reveal)),
+                "ink" => Ok(Self::Brush(BrushMode::Ink)),
+                "eraser" => Ok(Self::Brush(BrushMode::Eraser)),
+                "undo" => Ok(Self::Undo),
+                "redo" => Ok(Self::Redo),
+                "export" => Ok(Self::Export),
+                _ => Err(bad()),
+            }
+        }
+        let args: Vec<&lexpr::Value> = v.list_iter().ok_or_else(bad)?.collect();
+        match args.as_slice() {
+            [cmd, nm] if cmd.as_symbol() == Some("layer") => {
+                let r = nm.as_str().and_then(overlay::lookup).ok_or_else(bad)?;
+                Ok(Self::ToggleLayer(r.name))
+            },
+            [cmd, ev, data] if cmd.as_symbol() == Some("publish") => Ok(Self::Publish(
+                ev.as_str().ok_or_else(bad)?.to_owned(),
+                data.as_str().ok_or_else(bad)?.to_owned(),
+            )),
+            [cmd, ev] if cmd.as_symbol() == Some("publish") => Ok(Self::Publish(
+                ev.as_str().ok_or_else(bad)?.to_owned(),
+                String::new(),
+            )),
+            _ => Err(bad()),
+        }
+    }
+}
+
+/// A chord of keys that must all be held, and what it does.
+#[derive(Debug, Clone)]
+pub struct Binding {
+    pub chord: Vec<Keycode>,
+    pub command: Command,
+}
+impl Binding {
+    /// Chords are key names joined with "+", e.g. "LControl+Z".
+    pub fn parse(chord: &str, command: &lexpr::Value) -> Result<Self, Error> {
+        Ok(Self {
+            chord: chord.split('+')
+                .map(|k| k.trim().parse().map_err(|_| Error::BadKey(k.to_owned())))
+                .collect::<Result<_, _>>()?,
+            command: Command::parse(command)?,
+        })
+    }
+    /// Parse a bindings alist, e.g. (("LMeta" . draw) ("F9" . (layer "automata")))
+    pub fn parse_all(v: &lexpr::Value) -> Result<Vec<Self>, Error> {
+        let bad = |v: &lexpr::Value| Error::BadCommand(v.to_string());
+        v.list_iter().ok_or_else(|| bad(v))?
+            .map(|ent| {
+                let c = ent.as_cons().ok_or_else(|| bad(ent))?;
+                Self::parse(c.car().as_str().ok_or_else(|| bad(ent))?, c.cdr())
+            })
+            .collect()
+    }
+    fn is_down(&self, keys: &[Keycode]) -> bool {
+        self.chord.iter().all(|k| keys.contains(k))
+    }
 }
+pub fn default_bindings() -> Vec<Binding> {
+    vec![
+        Binding { chord: vec![Keycode::LMeta], command: Command::Drawing },
+        Binding { chord: vec![Keycode::RMeta], command: Command::EraseAll },
+    ]
+}
+
+/// The part of the desktop the overlay covers, in the pointer's coordinates.
+/// With two 1080p monitors side by side, the right one is 1920,0,1920,1080.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+impl Region {
+    fn from_fields(fields: &[i64], src: &str) -> Result<Self, Error> {
+        let bad = || Error::BadRegion(src.to_owned());
+        match fields {
+            [x, y, w, h] if *w > 0 && *h > 0 => Ok(Self {
+                x: (*x).try_into().map_err(|_| bad())?,
+                y: (*y).try_into().map_err(|_| bad())?,
+                width: (*w).try_into().map_err(|_| bad())?,
+                height: (*h).try_into().map_err(|_| bad())?,
+            }),
+            _ => Err(bad()),
+        }
+    }
+    /// Parse "X,Y,WIDTH,HEIGHT".
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let fields = s.split(',')
+            .map(|f| f.trim().parse().map_err(|_| Error::BadRegion(s.to_owned())))
+            .collect::<Result<Vec<i64>, _>>()?;
+        Self::from_fields(&fields, s)
+    }
+    /// Parse a list of four integers, e.g. (1920 0 1920 1080).
+    pub fn from_sexp(v: &lexpr::Value) -> Result<Self, Error> {
+        let bad = || Error::BadRegion(v.to_string());
+        let fields = v.list_iter().ok_or_else(bad)?
+            .map(|f| f.as_i64().ok_or_else(bad))
+            .collect::<Result<Vec<i64>, _>>()?;
+        Self::from_fields(&fields, &v.to_string())
+    }
+    /// Where a pointer position falls, from (0, 0) at the top left to (1, 1) at the bottom right.
+    pub fn normalize(&self, (x, y): (i32, i32)) -> glam::Vec2 {
+        glam::Vec2::new(
+            (x - self.x) as f32 + 0.5,
+            (y - self.y) as f32 + 0.5,
+        ) / glam::Vec2::new(self.width as f32, self.height as f32)
+    }
+}
+
 pub struct Input {
     pub device: device_query::DeviceState,
+    region: Option<Region>,
+    bindings: Vec<Binding>,
+    held: Vec<usize>, // indices of bindings whose chord is down
+    pressed: Vec<usize>, // indices of bindings whose chord went down this frame
 }
 impl Input {
-    pub fn new() -> Self {
+    pub fn new(bindings: &[Binding], region: Option<Region>) -> Self {
         Self {
             device: device_query::DeviceState::new(),
+            region,
+            bindings: bindings.to_vec(),
+            held: Vec::new(),
+            pressed: Vec::new(),
         }
     }
     pub fn get_mouse(&self) -> (i32, i32) {
         self.device.get_mouse().coords
     }
-    pub fn get_command(&mut self) -> Command {
+    /// The pointer relative to the overlay, from (0, 0) at the top left to (1, 1) at the bottom right.
+    /// Without a configured region, the overlay is taken to cover a screen of size dims at the origin.
+    pub fn pointer(&self, dims: glam::Vec2) -> glam::Vec2 {
+        let region = self.region.unwrap_or(Region { x: 0, y: 0, width: dims.x as u32, height: dims.y as u32 });
+        region.normalize(self.get_mouse())
+    }
+    /// Poll the keyboard. When chords overlap, only the largest one held counts,
+    /// so holding LControl+Z does not also fire a binding for Z alone.
+    pub fn update(&mut self) {
         let keys = self.device.get_keys();
-        if keys.contains(&device_query::Keycode::LMeta) {
-            Command::Drawing
-        } else if keys.contains(&device_query::Keycode::RMeta) {
-            Command::EraseAll
-        } else {
-            Command::None
-        }
+        let down: Vec<usize> = (0..self.bindings.len())
+            .filter(|i| self.bindings[*i].is_down(&keys))
+            .collect();
+        let held: Vec<usize> = down.iter().copied()
+            .filter(|i| !down.iter().any(|j| {
+                let (a, b) = (&self.bindings[*i].chord, &self.bindings[*j].chord);
+                b.len() > a.len() && a.iter().all(|k| b.contains(k))
+            }))
+            .collect();
+        self.pressed = held.iter().copied().filter(|i| !self.held.contains(i)).collect();
+        self.held = held;
+    }
+    pub fn is_held(&self, cmd: &Command) -> bool {
+        self.held.iter().any(|i| self.bindings[*i].command == *cmd)
+    }
+    pub fn pressed(&self) -> impl Iterator<Item = &Command> {
+        self.pressed.iter().map(|i| &self.bindings[*i].command)
+    }
+    pub fn was_pressed(&self, cmd: &Command) -> bool {
+        self.pressed().any(|c| c == cmd)
     }
 }
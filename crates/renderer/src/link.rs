@@ -0,0 +1,101 @@
+use teleia::*;
+
+use std::{sync::mpsc, time::{Duration, Instant}};
+
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// A connection to an external service that may come and go.
+/// While disconnected, reconnection is attempted with exponential backoff.
+/// Connecting happens on a background thread, so an unreachable service never stalls a frame.
+pub struct Link<T> {
+    pub name: &'static str,
+    pub addr: String,
+    conn: Option<T>,
+    connecting: Option<mpsc::Receiver<Result<T, String>>>,
+    enabled: bool,
+    failures: u32,
+    next_attempt: Instant,
+    last_error: Option<String>,
+}
+impl<T: Send + 'static> Link<T> {
+    pub fn new(name: &'static str, addr: &str) -> Self {
+        Self {
+            name,
+            addr: addr.to_owned(),
+            conn: None,
+            connecting: None,
+            enabled: true,
+            failures: 0,
+            next_attempt: Instant::now(),
+            last_error: None,
+        }
+    }
+    pub fn is_connected(&self) -> bool {
+        self.conn.is_some()
+    }
+    /// True if connected, or if we are deliberately not connecting.
+    pub fn is_healthy(&self) -> bool {
+        self.conn.is_some() || !self.enabled
+    }
+    /// Disconnect and stop trying to reconnect.
+    pub fn disable(&mut self) {
+        self.conn = None;
+        self.connecting = None;
+        self.enabled = false;
+    }
+    pub fn get(&mut self) -> Option<&mut T> {
+        self.conn.as_mut()
+    }
+    /// Pick up the result of a connection attempt, or start one in the background
+    /// if we are disconnected and the backoff has elapsed.
+    pub fn poll<F>(&mut self, connect: F) where F: FnOnce(&str) -> Erm<T> + Send + 'static {
+        if let Some(rx) = &self.connecting {
+            match rx.try_recv() {
+                Ok(Ok(c)) => {
+                    log::info!("connected to {} at {}", self.name, self.addr);
+                    self.connecting = None;
+                    self.conn = Some(c);
+                    self.failures = 0;
+                    self.last_error = None;
+                },
+                Ok(Err(e)) => { self.connecting = None; self.fail(e) },
+                Err(mpsc::TryRecvError::Empty) => {},
+                Err(mpsc::TryRecvError::Disconnected) => { self.connecting = None; self.fail("connection attempt panicked") },
+            }
+            return
+        }
+        if !self.enabled || self.conn.is_some() || Instant::now() < self.next_attempt { return }
+        let (tx, rx) = mpsc::channel();
+        let addr = self.addr.clone();
+        std::thread::spawn(move || {
+            // the receiver is gone if the link was disabled or reset meanwhile
+            let _ = tx.send(connect(&addr).map_err(|e| e.to_string()));
+        });
+        self.connecting = Some(rx);
+    }
+    /// Drop the current connection (if any) and schedule a reconnect.
+    pub fn fail<E>(&mut self, e: E) where E: std::fmt::Display {
+        let backoff = BACKOFF_MIN.saturating_mul(1 << self.failures.min(8)).min(BACKOFF_MAX);
+        log::warn!("{} at {} unavailable, retrying in {}s: {}", self.name, self.addr, backoff.as_secs(), e);
+        self.conn = None;
+        self.failures += 1;
+        self.next_attempt = Instant::now() + backoff;
+        self.last_error = Some(e.to_string());
+    }
+    pub fn status(&self) -> String {
+        if self.conn.is_some() {
+            format!("{}: ok", self.name)
+        } else if !self.enabled {
+            format!("{}: disabled", self.name)
+        } else if self.connecting.is_some() {
+            format!("{}: connecting", self.name)
+        } else {
+            let wait = self.next_attempt.saturating_duration_since(Instant::now()).as_secs();
+            match &self.last_error {
+                Some(e) => format!("{}: retrying in {}s ({})", self.name, wait, e),
+                None => format!("{}: connecting", self.name),
+            }
+        }
+    }
+}
@@ -1,22 +1,113 @@
 #![allow(dead_code, unused_variables)]
 mod assets;
+mod config;
 mod terminal;
 mod background;
 mod toggle;
+mod param;
+mod persist;
 mod overlay;
 mod input;
+mod link;
+mod spool;
+mod recording;
+mod capture;
+mod status;
 
 use teleia::*;
-use clap::{command, Command};
+use clap::{command, Arg, Command};
+
+fn layer_args() -> [Arg; 2] {
+    [
+        Arg::new("enable")
+            .long("enable")
+            .value_name("OVERLAYS")
+            .help("Comma-separated overlays to run, bottom layer first (automata, shader, drawing, tcg, model, loopback)"),
+        Arg::new("disable")
+            .long("disable")
+            .value_name("OVERLAYS")
+            .help("Comma-separated overlays to leave out"),
+    ]
+}
+
+/// Playback speed must be positive, or replays never advance.
+fn parse_speed(s: &str) -> Result<f64, String> {
+    let v = s.parse::<f64>().map_err(|e| e.to_string())?;
+    if v.is_finite() && v > 0.0 { Ok(v) } else { Err("speed must be greater than 0".to_owned()) }
+}
+
+fn resolve_layers(cm: &clap::ArgMatches) -> Erm<Vec<&'static str>> {
+    Ok(overlay::resolve_layers(
+        cm.get_one::<String>("enable").map(|s| s.as_str()),
+        cm.get_one::<String>("disable").map(|s| s.as_str()),
+    )?)
+}
 
 pub fn main() -> Erm<()> {
     let matches = command!()
         .propagate_version(true)
         .subcommand_required(true)
         .arg_required_else_help(true)
+        .args(config::Config::args())
         .subcommand(
             Command::new("overlay")
                 .about("Run the full-screen transparent overlay")
+                .args(layer_args())
+        )
+        .subcommand(
+            Command::new("record")
+                .about("Record overlay bus traffic to a file")
+                .arg(Arg::new("file").required(true))
+        )
+        .subcommand(
+            Command::new("replay")
+                .about("Run the overlay with messages from a recording instead of the bus")
+                .arg(Arg::new("file").required(true))
+                .arg(
+                    Arg::new("speed")
+                        .long("speed")
+                        .value_name("FACTOR")
+                        .value_parser(parse_speed)
+                        .default_value("1.0")
+                        .help("Playback speed multiplier")
+                )
+                .args(layer_args())
+        )
+        .subcommand(
+            Command::new("render-frames")
+                .about("Replay a recording offscreen and write the composited frames as PNG")
+                .arg(Arg::new("file").required(true))
+                .arg(
+                    Arg::new("out")
+                        .long("out")
+                        .value_name("DIR")
+                        .default_value("frames")
+                        .help("Directory to write frames to")
+                )
+                .arg(
+                    Arg::new("every")
+                        .long("every")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("1")
+                        .help("Only write every Nth frame")
+                )
+                .arg(
+                    Arg::new("frames")
+                        .long("frames")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("Stop after N frames instead of when the recording ends")
+                )
+                .arg(
+                    Arg::new("speed")
+                        .long("speed")
+                        .value_name("FACTOR")
+                        .value_parser(parse_speed)
+                        .default_value("1.0")
+                        .help("Playback speed multiplier")
+                )
+                .args(layer_args())
         )
         .subcommand(
             Command::new("model-terminal")
@@ -24,20 +115,49 @@ pub fn main() -> Erm<()> {
         )
         .get_matches();
     match matches.subcommand() {
-        Some(("overlay", _cm)) => {
-            teleia::run("LCOLONQ", 1920, 1080, teleia::Options::OVERLAY, |ctx| {
-                overlay::Overlays::new(ctx, vec![
-                    Box::new(overlay::automata::Overlay::new(ctx)),
-                    Box::new(overlay::shader::Overlay::new(ctx)),
-                    Box::new(overlay::drawing::Overlay::new(ctx)),
-                    Box::new(overlay::tcg::Overlay::new(ctx)),
-                    // Box::new(overlay::model::Overlay::new(ctx)),
-                    // Box::new(overlay::loopback::Overlay::new(ctx)),
-                ])
+        Some(("overlay", cm)) => {
+            let cfg = config::Config::from_matches(cm)?;
+            let layers = resolve_layers(cm)?;
+            teleia::run("LCOLONQ", 1920, 1080, teleia::Options::OVERLAY, move |ctx| {
+                overlay::Overlays::new(ctx, &cfg, &layers)
             })?;
         },
-        Some(("model-terminal", _cm)) => {
-            teleia::run("LCOLONQ", 1920, 1080, teleia::Options::HIDDEN, overlay::model::Terminal::new)?;
+        Some(("record", cm)) => {
+            env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+            let cfg = config::Config::from_matches(cm)?;
+            let path = cm.get_one::<String>("file").expect("missing file");
+            recording::record(&cfg, path, newton_messages::EVENTS)?;
+        },
+        Some(("replay", cm)) => {
+            let cfg = config::Config::from_matches(cm)?;
+            let layers = resolve_layers(cm)?;
+            let entries = recording::load(cm.get_one::<String>("file").expect("missing file"))?;
+            let speed = *cm.get_one::<f64>("speed").expect("missing speed");
+            teleia::run("LCOLONQ", 1920, 1080, teleia::Options::OVERLAY, move |ctx| {
+                let mut ret = overlay::Overlays::new(ctx, &cfg, &layers);
+                ret.set_replay(recording::Player::new(entries.clone(), speed));
+                ret
+            })?;
+        },
+        Some(("render-frames", cm)) => {
+            let cfg = config::Config::from_matches(cm)?;
+            let layers = resolve_layers(cm)?;
+            let entries = recording::load(cm.get_one::<String>("file").expect("missing file"))?;
+            let speed = *cm.get_one::<f64>("speed").expect("missing speed");
+            let out = cm.get_one::<String>("out").expect("missing out").clone();
+            let every = *cm.get_one::<u64>("every").expect("missing every");
+            let limit = cm.get_one::<u64>("frames").copied();
+            teleia::run("LCOLONQ", 1920, 1080, teleia::Options::HIDDEN, move |ctx| {
+                let mut overlays = overlay::Overlays::new(ctx, &cfg, &layers);
+                overlays.set_replay(recording::Player::new(entries.clone(), speed));
+                capture::Capture::new(overlays, &out, every, limit)
+            })?;
+        },
+        Some(("model-terminal", cm)) => {
+            let cfg = config::Config::from_matches(cm)?;
+            teleia::run("LCOLONQ", 1920, 1080, teleia::Options::HIDDEN, move |ctx| {
+                overlay::model::Terminal::new(ctx, &cfg)
+            })?;
         },
         _ => unreachable!("no subcommand"),
     }
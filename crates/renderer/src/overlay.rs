@@ -8,9 +8,9 @@ pub mod loopback;
 use teleia::*;
 
 use std::f32::consts::PI;
-use byteorder::{LE, ReadBytesExt};
+use newton_messages::Message;
 
-use crate::{assets, fig, toggle, input, background};
+use crate::{assets, config, fig, toggle, param, persist, input, background, link, spool, recording, status};
 
 pub struct Chat {
     author: String,
@@ -42,52 +42,75 @@ pub struct Info {
     muzak_author: Option<String>,
 }
 
+/// Events handled by Overlays itself rather than any one overlay.
+pub const CORE_EVENTS: &[&[u8]] = &[
+    b"overlay reset",
+    b"overlay reset preserve",
+    b"overlay tracking",
+    b"overlay background frame",
+    b"overlay toggle",
+    b"overlay toggle set",
+    b"overlay toggle unset",
+    b"overlay toggle list",
+    b"overlay param set",
+    b"overlay param unset",
+    b"overlay param list",
+    b"overlay info emacs",
+    b"overlay info emacs cursor",
+    b"overlay info credits music",
+    b"overlay info credits music clear",
+    b"overlay chat",
+    b"overlay layers set",
+    b"overlay layers enable",
+    b"overlay layers disable",
+];
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    UnknownOverlay(String),
+}
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownOverlay(nm) => write!(f, "unknown overlay: {}", nm),
+        }
+    }
+}
+impl std::error::Error for Error {}
+
 pub struct State {
     assets: assets::Assets,
-    redis: redis::Client,
-    redis_conn: redis::Connection,
+    redis: link::Link<redis::Connection>,
+    spool: spool::Spool,
     model: scene::Scene,
     model_neck_base: glam::Mat4,
-    fig_binary: fig::BinaryClient,
+    bus: link::Link<fig::BinaryClient>,
     tracking: Tracking,
     info: Info,
     chat: Chat,
     toggles: toggle::Toggles,
+    params: param::Params,
+    state_file: std::path::PathBuf,
+    persistent: bool, // whether toggle and parameter changes are saved
+    restored: bool, // whether saved toggles and parameters have been loaded
+    replaying: bool, // whether messages come from a recording, and so should not be published
     input: input::Input,
     backgrounds: background::Backgrounds,
 }
 impl State {
-    pub fn new(ctx: &context::Context) -> Self {
+    pub fn new(ctx: &context::Context, cfg: &config::Config) -> Self {
         let model = scene::Scene::from_gltf(ctx, include_bytes!("assets/scenes/lcolonq.vrm")); 
         let model_neck_base = model.nodes_by_name.get("J_Bip_C_Neck")
             .and_then(|i| model.nodes.get(*i))
             .expect("failed to find neck joint")
             .transform;
-        let redis = redis::Client::open("redis://shiro").unwrap();
-        let redis_conn = redis.get_connection().unwrap();
-        Self {
+        let mut ret = Self {
             assets: assets::Assets::new(ctx),
-            redis, redis_conn,
+            redis: link::Link::new("redis", &cfg.redis),
+            spool: spool::Spool::new(&cfg.spool),
             model,
             model_neck_base,
-            fig_binary: fig::BinaryClient::new("shiro:32051", &[
-                b"overlay reset",
-                b"overlay tracking",
-                b"overlay background frame",
-                b"overlay toggle",
-                b"overlay toggle set",
-                b"overlay toggle unset",
-                b"overlay info emacs",
-                b"overlay info emacs cursor",
-                b"overlay info credits music",
-                b"overlay info credits music clear",
-                b"overlay chat",
-                b"overlay avatar text",
-                b"overlay shader",
-                b"overlay shader chat",
-                b"overlay automata spawn",
-                b"overlay tcg generate",
-            ]).expect("failed to connect to bus"),
+            bus: link::Link::new("bus", &cfg.bus),
             tracking: Tracking {
                 eyes: (1.0, 1.0),
                 mouth: 0.0,
@@ -101,12 +124,139 @@ impl State {
             },
             chat: Chat::new(),
             toggles: toggle::Toggles::new(),
+            params: param::Params::new(),
+            state_file: std::path::PathBuf::from(&cfg.state_file),
+            persistent: true,
+            restored: false,
+            replaying: false,
             backgrounds: background::Backgrounds::new(ctx),
-            input: input::Input::new(),
-        }
+            input: input::Input::new(&cfg.bindings, cfg.drawing_region),
+        };
+        ret.update_links();
+        ret.restore();
+        ret
     }
-    fn reset(&mut self, ctx: &context::Context, st: &mut state::State) {
+    /// Forget toggles and parameters, or if preserve is set, go back to
+    /// their saved values (dropping timed toggles and transitions).
+    fn reset(&mut self, ctx: &context::Context, st: &mut state::State, preserve: bool) {
         self.toggles.reset();
+        self.params.reset();
+        if preserve { self.restore(); } else { self.persist(); }
+    }
+    /// Saved toggles and parameters: those in Redis, overridden by any in the state file,
+    /// since it holds changes made while Redis was unavailable. None if neither is available.
+    fn saved(&mut self) -> Erm<Option<persist::Snapshot>> {
+        let file = persist::Snapshot::load_file(&self.state_file)?;
+        let redis = match self.redis.get() {
+            Some(conn) => Some(persist::Snapshot::load_redis(conn)?),
+            None => None,
+        };
+        Ok(match (redis, file) {
+            (Some(r), Some(f)) => Some(r.merged(f)),
+            (r, f) => r.or(f),
+        })
+    }
+    /// Load saved toggles and parameters.
+    fn restore(&mut self) {
+        let res: Erm<()> = (|| {
+            let Some(snap) = self.saved()? else { return Ok(()) };
+            for (nm, v) in snap.toggles { self.toggles.restore(&nm, v); }
+            for (nm, v) in snap.params { self.params.restore(&nm, v); }
+            self.restored = true;
+            Ok(())
+        })();
+        if let Err(e) = res {
+            log::warn!("failed to restore saved toggles and parameters: {}", e);
+        }
+    }
+    /// Save toggles and parameters to Redis, or to the state file if Redis is unavailable.
+    fn persist(&mut self) {
+        if !self.persistent { return }
+        let snap = persist::Snapshot {
+            toggles: self.toggles.persisted(),
+            params: self.params.persisted(),
+        };
+        if self.redis.is_connected() {
+            if let Err(e) = self.redis_write(&snap.redis_cmds()) {
+                log::warn!("failed to save toggles and parameters: {}", e);
+            }
+        }
+        if !self.redis.is_connected() {
+            if let Err(e) = snap.save_file(&self.state_file) {
+                log::warn!("failed to save toggles and parameters to {}: {}", self.state_file.display(), e);
+            }
+        }
+    }
+    /// Hand state saved while Redis was unavailable over to Redis, keeping whatever
+    /// Redis already held that the state file does not mention.
+    fn sync_state_file(&mut self) {
+        let res: Erm<()> = (|| {
+            if let Some(file) = persist::Snapshot::load_file(&self.state_file)? {
+                let Some(conn) = self.redis.get() else { return Ok(()) };
+                let snap = persist::Snapshot::load_redis(conn)?.merged(file);
+                self.redis_write(&snap.redis_cmds())?;
+                std::fs::remove_file(&self.state_file)?;
+                log::info!("moved saved toggles and parameters from {} to Redis", self.state_file.display());
+            }
+            Ok(())
+        })();
+        if let Err(e) = res {
+            log::warn!("failed to sync {}: {}", self.state_file.display(), e);
+        }
+    }
+    /// Run a batch of Redis writes atomically, or spool them to disk if
+    /// Redis is unavailable so they can be replayed after reconnecting.
+    /// Batches the server rejects are returned as errors rather than spooled.
+    pub fn redis_write(&mut self, cmds: &[redis::Cmd]) -> Erm<()> {
+        if self.spool.pending() == 0 {
+            if let Some(conn) = self.redis.get() {
+                let mut pipe = redis::pipe();
+                pipe.atomic();
+                for c in cmds { pipe.add_command(c.clone()); }
+                match pipe.query::<()>(conn) {
+                    Ok(()) => return Ok(()),
+                    Err(e) if spool::is_connection_error(&e) => self.redis.fail(e),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        self.spool.push(cmds)
+    }
+    fn update_links(&mut self) {
+        let was_connected = self.redis.is_connected();
+        self.redis.poll(|addr| {
+            let client = redis::Client::open(addr)?;
+            Ok(client.get_connection_with_timeout(std::time::Duration::from_secs(1))?)
+        });
+        if self.spool.pending() > 0 {
+            if let Some(conn) = self.redis.get() {
+                if let Err(e) = self.spool.flush(conn) { self.redis.fail(e); }
+            }
+        }
+        if !was_connected && self.redis.is_connected() && self.persistent {
+            // Redis connects in the background, so it is usually not up yet when we start,
+            // and anything saved to the state file meanwhile only holds part of the state
+            if !self.restored || self.state_file.exists() { self.restore(); }
+            self.sync_state_file();
+        }
+    }
+    /// Send a message to the bus, dropping it if the bus is unavailable.
+    pub fn publish(&mut self, msg: &Message) {
+        self.publish_raw(msg.event(), &msg.encode());
+    }
+    pub fn publish_raw(&mut self, event: &[u8], data: &[u8]) {
+        if let Some(bus) = self.bus.get() {
+            if let Err(e) = bus.publish(event, data) { self.bus.fail(e); }
+        }
+    }
+    /// A one-line summary of external services, or None if everything is healthy.
+    pub fn diagnostics(&self) -> Option<String> {
+        if self.bus.is_healthy() && self.redis.is_healthy() && self.spool.pending() == 0 { return None }
+        let mut ret = vec![self.bus.status(), self.redis.status()];
+        if self.spool.pending() > 0 {
+            ret.push(format!("spool: {} pending", self.spool.pending()));
+        }
+        Some(ret.join(" | "))
     }
     fn update(&mut self, ctx: &context::Context, st: &mut state::State) -> Erm<()> {
         st.move_camera(
@@ -115,6 +265,9 @@ impl State {
             &glam::Vec3::new(0.0, 0.0, -1.0),
             &glam::Vec3::new(0.0, 1.0, 0.0),
         );
+        self.update_links();
+        self.toggles.update(ctx, st);
+        self.input.update();
         let (x, y) = self.input.get_mouse();
         self.info.mouse_cursor = (x as f32, y as f32);
         // update model head transform based on tracking state
@@ -126,136 +279,403 @@ impl State {
 }
 
 pub trait Overlay {
+    /// Bus events this overlay wants passed to handle_message.
+    fn events(&self) -> &'static [&'static [u8]] {
+        &[]
+    }
     fn reset(&mut self, ctx: &context::Context, st: &mut state::State, ost: &mut State) -> Erm<()> {
         Ok(())
     }
     fn update(&mut self, ctx: &context::Context, st: &mut state::State, ost: &mut State) -> Erm<()> {
         Ok(())
     }
-    fn handle_binary(&mut self, ctx: &context::Context, st: &mut state::State, ost: &mut State, msg: &fig::BinaryMessage) -> Erm<()> {
+    fn handle_message(&mut self, ctx: &context::Context, st: &mut state::State, ost: &mut State, msg: &Message) -> Erm<()> {
         Ok(())
     }
     fn render(&mut self, ctx: &context::Context, st: &mut state::State, ost: &mut State) -> Erm<()> {
         Ok(())
     }
+    /// Overlay-specific entries for the status report.
+    fn status(&self) -> Vec<(&'static str, lexpr::Value)> {
+        Vec::new()
+    }
+}
+
+pub struct Registered {
+    pub name: &'static str,
+    pub default: bool,
+    pub events: &'static [&'static [u8]], // what the overlay's events() returns, known before it exists
+    pub new: fn(&context::Context, &config::Config) -> Erm<Box<dyn Overlay>>,
+}
+/// Every overlay that can be enabled, in default z-order (bottom first).
+pub const REGISTRY: &[Registered] = &[
+    Registered { name: "automata", default: true, events: automata::EVENTS, new: |ctx, cfg| Ok(Box::new(automata::Overlay::new(ctx, cfg))) },
+    Registered { name: "shader", default: true, events: shader::EVENTS, new: |ctx, _| Ok(Box::new(shader::Overlay::new(ctx))) },
+    Registered { name: "drawing", default: true, events: drawing::EVENTS, new: |ctx, _| Ok(Box::new(drawing::Overlay::new(ctx))) },
+    Registered { name: "tcg", default: true, events: tcg::EVENTS, new: |ctx, _| Ok(Box::new(tcg::Overlay::new(ctx))) },
+    Registered { name: "model", default: false, events: model::EVENTS, new: |ctx, _| Ok(Box::new(model::Overlay::new(ctx))) },
+    Registered { name: "loopback", default: false, events: &[], new: |ctx, cfg| Ok(Box::new(loopback::Overlay::new(ctx, cfg)?)) },
+];
+pub fn lookup(nm: &str) -> Option<&'static Registered> {
+    REGISTRY.iter().find(|r| r.name == nm)
+}
+/// Parse a comma or space separated list of overlay names.
+pub fn parse_layers(s: &str) -> Result<Vec<&'static str>, Error> {
+    s.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|nm| !nm.is_empty())
+        .map(|nm| lookup(nm).map(|r| r.name).ok_or_else(|| Error::UnknownOverlay(nm.to_owned())))
+        .collect()
+}
+pub fn resolve_layers(enable: Option<&str>, disable: Option<&str>) -> Result<Vec<&'static str>, Error> {
+    let mut ret = match enable {
+        Some(s) => parse_layers(s)?,
+        None => REGISTRY.iter().filter(|r| r.default).map(|r| r.name).collect(),
+    };
+    if let Some(s) = disable {
+        let dis = parse_layers(s)?;
+        ret.retain(|nm| !dis.contains(nm));
+    }
+    Ok(ret)
+}
+
+pub struct Layer {
+    name: &'static str,
+    active: bool,
+    overlay: Box<dyn Overlay>,
+    failed_this_frame: bool,
+    failures: u32, // consecutive frames with an error
+    last_error: Option<String>,
+}
+impl Layer {
+    pub fn new(name: &'static str, overlay: Box<dyn Overlay>) -> Self {
+        Self {
+            name,
+            active: false,
+            overlay,
+            failed_this_frame: false,
+            failures: 0,
+            last_error: None,
+        }
+    }
+    pub fn is_faulted(&self) -> bool {
+        self.failures > 0
+    }
+    /// Record the result of calling into the overlay, logging new errors.
+    fn check(&mut self, what: &str, res: Erm<()>) {
+        if let Err(e) = res {
+            let e = e.to_string();
+            if self.last_error.as_ref() != Some(&e) {
+                log::warn!("overlay {} failed to {}: {}", self.name, what, e);
+            }
+            self.last_error = Some(e);
+            self.failed_this_frame = true;
+        }
+    }
+    fn end_frame(&mut self) {
+        if self.failed_this_frame {
+            self.failures += 1;
+        } else {
+            self.failures = 0;
+            self.last_error = None;
+        }
+        self.failed_this_frame = false;
+    }
 }
 
 pub struct Overlays {
     state: State,
-    overlays: Vec<Box<dyn Overlay>>
+    cfg: config::Config,
+    layers: Vec<Layer>,
+    subscriptions: Vec<&'static [u8]>, // every event any overlay could want, so toggling layers never resubscribes
+    replay: Option<recording::Player>,
+    timing: status::Timing,
+    endpoint: Option<status::Endpoint>,
 }
 impl Overlays {
-    pub fn new(ctx: &context::Context, overlays: Vec<Box<dyn Overlay>>) -> Self {
-        Self {
-            state: State::new(ctx),
-            overlays,
+    pub fn new(ctx: &context::Context, cfg: &config::Config, layers: &[&'static str]) -> Self {
+        let mut ret = Self {
+            state: State::new(ctx, cfg),
+            cfg: cfg.clone(),
+            layers: Vec::new(),
+            subscriptions: Self::all_subscriptions(),
+            replay: None,
+            timing: status::Timing::new(),
+            endpoint: cfg.status_socket.as_ref().and_then(|p| match status::Endpoint::bind(p) {
+                Ok(e) => Some(e),
+                Err(e) => { log::warn!("failed to serve status on {}: {}", p, e); None },
+            }),
+        };
+        ret.set_layers(ctx, layers);
+        ret
+    }
+    pub fn reset(&mut self, ctx: &context::Context, st: &mut state::State, preserve: bool) {
+        self.state.reset(ctx, st, preserve);
+        for l in self.layers.iter_mut() {
+            let res = l.overlay.reset(ctx, st, &mut self.state);
+            l.check("reset", res);
+        }
+    }
+    /// Take messages from a recording instead of the live bus.
+    pub fn set_replay(&mut self, player: recording::Player) {
+        self.state.bus.disable();
+        // start from a clean slate, and keep the replay's changes out of the live saved state
+        self.state.persistent = false;
+        self.state.replaying = true;
+        self.state.toggles.reset();
+        self.state.params.reset();
+        self.replay = Some(player);
+    }
+    pub fn replay_finished(&self) -> bool {
+        self.replay.as_ref().is_some_and(|p| p.is_finished())
+    }
+    pub fn active_layers(&self) -> Vec<&'static str> {
+        self.layers.iter().filter(|l| l.active).map(|l| l.name).collect()
+    }
+    /// Activate exactly the named overlays, in the given z-order.
+    /// Overlays are constructed the first time they are enabled, and keep
+    /// their state while disabled.
+    pub fn set_layers(&mut self, ctx: &context::Context, names: &[&'static str]) {
+        for nm in names {
+            if self.layers.iter().any(|l| l.name == *nm) { continue }
+            if let Some(r) = lookup(nm) {
+                match (r.new)(ctx, &self.cfg) {
+                    Ok(overlay) => self.layers.push(Layer::new(r.name, overlay)),
+                    Err(e) => log::warn!("failed to start overlay {}: {}", nm, e),
+                }
+            }
+        }
+        for l in self.layers.iter_mut() {
+            l.active = names.contains(&l.name);
+        }
+        self.layers.sort_by_key(|l| names.iter().position(|nm| *nm == l.name).unwrap_or(usize::MAX));
+        log::info!("overlay layers: {}", self.active_layers().join(", "));
+    }
+    /// The core events plus everything any registered overlay handles, active or not.
+    /// Messages for inactive overlays are dropped in dispatch.
+    fn all_subscriptions() -> Vec<&'static [u8]> {
+        let mut ret: Vec<&'static [u8]> = CORE_EVENTS.to_vec();
+        for ev in REGISTRY.iter().flat_map(|r| r.events) {
+            if !ret.contains(ev) { ret.push(ev); }
+        }
+        ret
+    }
+    /// Everything our stream tooling wants to know about overlay health, as an alist.
+    pub fn status(&self, st: &state::State) -> lexpr::Value {
+        let mut ret = vec![
+            ("tick", lexpr::Value::from(st.tick)),
+            ("layers", lexpr::Value::list(self.active_layers())),
+            ("faulted", lexpr::Value::list(self.layers.iter().filter(|l| l.active && l.is_faulted()).map(|l| {
+                lexpr::Value::cons(l.name, l.last_error.as_deref().unwrap_or(""))
+            }))),
+            ("toggles", self.state.toggles.status(st)),
+            ("params", self.state.params.status(st)),
+            ("timing", self.timing.status()),
+            ("bus", lexpr::Value::from(self.state.bus.is_connected())),
+            ("redis", lexpr::Value::from(self.state.redis.is_connected())),
+            ("spool", lexpr::Value::from(self.state.spool.pending() as u64)),
+        ];
+        for l in self.layers.iter().filter(|l| l.active) {
+            let entries = l.overlay.status();
+            if !entries.is_empty() { ret.push((l.name, status::alist(entries))); }
+        }
+        status::alist(ret)
+    }
+    fn report_status(&mut self, st: &state::State) {
+        let period = (self.cfg.status_interval * 60.0) as u64;
+        let publish = period > 0 && st.tick % period == 0;
+        let clients = self.endpoint.as_ref().map(|e| e.accept()).unwrap_or_default();
+        if !publish && clients.is_empty() { return }
+        let report = self.status(st).to_string();
+        if !clients.is_empty() { status::Endpoint::serve(clients, &report); }
+        if publish && !self.state.replaying { self.state.publish(&Message::Status(report)); }
+    }
+    /// Keyboard commands that are not specific to any one overlay.
+    fn handle_commands(&mut self, ctx: &context::Context) {
+        let pressed: Vec<input::Command> = self.state.input.pressed().cloned().collect();
+        for cmd in pressed {
+            match cmd {
+                input::Command::ToggleLayer(nm) => {
+                    let mut layers = self.active_layers();
+                    if layers.contains(&nm) { layers.retain(|l| *l != nm); } else { layers.push(nm); }
+                    self.set_layers(ctx, &layers);
+                },
+                input::Command::Publish(ev, data) => self.state.publish_raw(ev.as_bytes(), data.as_bytes()),
+                _ => {},
+            }
+        }
+    }
+    fn handle_layers(&mut self, ctx: &context::Context, msg: &Message, names: &str) {
+        let names = match parse_layers(names) {
+            Ok(names) => names,
+            Err(e) => { log::warn!("malformed layers update: {}", e); return; },
+        };
+        let mut layers = self.active_layers();
+        match msg {
+            Message::LayersSet(_) => layers = names,
+            Message::LayersEnable(_) => {
+                layers.retain(|nm| !names.contains(nm));
+                layers.extend(names);
+            },
+            Message::LayersDisable(_) => layers.retain(|nm| !names.contains(nm)),
+            _ => {},
         }
+        self.set_layers(ctx, &layers);
     }
-    pub fn reset(&mut self, ctx: &context::Context, st: &mut state::State) -> Erm<()> {
-        self.state.reset(ctx, st);
-        for ov in self.overlays.iter_mut() {
-            ov.reset(ctx, st, &mut self.state)?;
+    fn handle_message(&mut self, ctx: &context::Context, st: &mut state::State, msg: &Message) -> Erm<()> {
+        match msg {
+            Message::Reset => self.reset(ctx, st, false),
+            Message::ResetPreserve => self.reset(ctx, st, true),
+            Message::LayersSet(nms) | Message::LayersEnable(nms) | Message::LayersDisable(nms) =>
+                self.handle_layers(ctx, msg, nms),
+            Message::Tracking(t) => {
+                self.state.tracking.eyes = (t.eye_left, t.eye_right);
+                self.state.tracking.mouth = t.mouth;
+                self.state.tracking.neck = glam::Quat::from_euler(
+                    glam::EulerRot::XYZ,
+                    t.euler_x.to_radians(),
+                    PI - t.euler_y.to_radians(),
+                    t.euler_z.to_radians() + PI/2.0,
+                );
+            },
+            Message::BackgroundFrame(f) => self.state.backgrounds.update(ctx, f),
+            Message::Toggle(t) => {
+                self.state.toggles.toggle(ctx, st, &t.name, t.duration);
+                self.state.persist();
+            },
+            Message::ToggleSet(t) => {
+                self.state.toggles.set(ctx, st, &t.name, true, t.duration);
+                self.state.persist();
+            },
+            Message::ToggleUnset(t) => {
+                self.state.toggles.set(ctx, st, &t.name, false, t.duration);
+                self.state.persist();
+            },
+            Message::ToggleList => {
+                let states = self.state.toggles.status(st).to_string();
+                self.state.publish(&Message::ToggleStates(states));
+            },
+            Message::ParamSet(p) => {
+                self.state.params.set(st, &p.name, p.value.clone(), p.duration);
+                self.state.persist();
+            },
+            Message::ParamUnset(nm) => {
+                self.state.params.unset(nm);
+                self.state.persist();
+            },
+            Message::ParamList => {
+                let states = self.state.params.status(st).to_string();
+                self.state.publish(&Message::ParamStates(states));
+            },
+            Message::InfoEmacs { heartrate } => self.state.info.emacs_heartrate = *heartrate,
+            Message::InfoEmacsCursor { x, y } => self.state.info.emacs_cursor = (*x, *y),
+            Message::InfoCreditsMusic(nm) => self.state.info.muzak_author = Some(nm.clone()),
+            Message::InfoCreditsMusicClear => self.state.info.muzak_author = None,
+            Message::Chat(c) => {
+                self.state.chat.author = c.author.clone();
+                self.state.chat.msg = c.msg.clone();
+                self.state.chat.time = c.time;
+                self.state.chat.biblicality = c.biblicality;
+            },
+            _ => {},
         }
         Ok(())
     }
 }
 impl teleia::state::Game for Overlays {
     fn update(&mut self, ctx: &context::Context, st: &mut state::State) -> Erm<()> {
+        let start = std::time::Instant::now();
         st.move_camera(
             ctx,
             &glam::Vec3::new(0.0, 0.0, 1.0),
             &glam::Vec3::new(0.0, 0.0, -1.0),
             &glam::Vec3::new(0.0, 1.0, 0.0),
         );
-        while let Some(msg) = self.state.fig_binary.pump()? {
-            for ov in self.overlays.iter_mut() {
-                ov.handle_binary(ctx, st, &mut self.state, &msg)?;
+        let subscriptions = self.subscriptions.clone();
+        self.state.bus.poll(move |addr| fig::BinaryClient::new(addr, &subscriptions));
+        loop {
+            let raw = if let Some(p) = &mut self.replay {
+                match p.pump(st.tick) {
+                    Some(msg) => msg,
+                    None => break,
+                }
+            } else {
+                match self.state.bus.get().map(|b| b.pump()) {
+                    Some(Ok(Some(msg))) => msg,
+                    Some(Err(e)) => { self.state.bus.fail(e); break; },
+                    _ => break,
+                }
+            };
+            let msg = match Message::decode(&raw.event, &raw.data) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    log::warn!("malformed {}: {}", String::from_utf8_lossy(&raw.event), e);
+                    continue;
+                },
+            };
+            for l in self.layers.iter_mut().filter(|l| l.active && l.overlay.events().contains(&msg.event())) {
+                let res = l.overlay.handle_message(ctx, st, &mut self.state, &msg);
+                l.check("handle message", res);
             }
-            if let Ok(t) = str::from_utf8(&msg.event) {
+            if let Ok(t) = str::from_utf8(&raw.event) {
                 log::info!("incoming: {}", t);
             }
-            match &*msg.event {
-                b"overlay reset" => self.reset(ctx, st)?,
-                b"overlay tracking" => {
-                    let res: Erm<()> = (|| {
-                        let mut reader = std::io::Cursor::new(&msg.data);
-                        let eye_left = reader.read_f32::<LE>()?;
-                        let eye_right = reader.read_f32::<LE>()?;
-                        let mouth = reader.read_f32::<LE>()?;
-                        let euler_x = reader.read_f32::<LE>()?.to_radians();
-                        let euler_y = PI - reader.read_f32::<LE>()?.to_radians();
-                        let euler_z = reader.read_f32::<LE>()?.to_radians() + PI/2.0;
-                        self.state.tracking.eyes = (eye_left, eye_right);
-                        self.state.tracking.mouth = mouth;
-                        self.state.tracking.neck = glam::Quat::from_euler(
-                            glam::EulerRot::XYZ, euler_x, euler_y, euler_z
-                        );
-                        Ok(())
-                    })();
-                    if let Err(e) = res { log::warn!("malformed tracking update: {}", e); }
-                },
-                b"overlay background frame" => {
-                    if let Some(f) = background::Frame::parse(&mut &*msg.data) {
-                        self.state.backgrounds.update(ctx, f);
-                    } else {
-                        log::warn!("malformed background frame");
-                    }
-                },
-                b"overlay toggle" => self.state.toggles.handle(ctx, st, msg),
-                b"overlay toggle set" => self.state.toggles.handle_set(ctx, st, msg, true),
-                b"overlay toggle unset" => self.state.toggles.handle_set(ctx, st, msg, false),
-                b"overlay info emacs" => {
-                    let mut reader = std::io::Cursor::new(&msg.data);
-                    match reader.read_i32::<LE>() {
-                        Ok(heartrate) => self.state.info.emacs_heartrate = heartrate,
-                        Err(e) => log::warn!("malformed Emacs update: {}", e),
-                    }
-                },
-                b"overlay info emacs cursor" => {
-                    let res: Erm<()> = (|| {
-                        let mut reader = std::io::Cursor::new(&msg.data);
-                        let cursor_x = fig::read_length_prefixed_utf8(&mut reader)?.parse()?;
-                        let cursor_y = fig::read_length_prefixed_utf8(&mut reader)?.parse()?;
-                        self.state.info.emacs_cursor = (cursor_x, cursor_y);
-                        Ok(())
-                    })();
-                    if let Err(e) = res { log::warn!("malformed Emacs cursor update: {}", e); }
-                },
-                b"overlay info credits music" => match str::from_utf8(&msg.data) {
-                    Ok(nm) => self.state.info.muzak_author = Some(nm.to_owned()),
-                    Err(e) => log::warn!("malformed music credits update: {}", e),
-                },
-                b"overlay info credits music clear" => { self.state.info.muzak_author = None; },
-                b"overlay chat" => {
-                    let res: Erm<()> = (|| {
-                        let mut reader = std::io::Cursor::new(&msg.data);
-                        let author = fig::read_length_prefixed_utf8(&mut reader)?;
-                        let msg = fig::read_length_prefixed_utf8(&mut reader)?;
-                        let time = fig::read_length_prefixed_utf8(&mut reader)?.parse()?;
-                        let biblicality = fig::read_length_prefixed_utf8(&mut reader)?.parse()?;
-                        self.state.chat.author = author;
-                        self.state.chat.msg = msg;
-                        self.state.chat.time = time;
-                        self.state.chat.biblicality = biblicality;
-                        Ok(())
-                    })();
-                    if let Err(e) = res { log::warn!("malformed chat update: {}", e); }
-                },
-                _ => {},
-            }
+            self.handle_message(ctx, st, &msg)?;
         }
         self.state.update(ctx, st)?;
-        for ov in self.overlays.iter_mut() {
-            ov.update(ctx, st, &mut self.state)?;
+        self.handle_commands(ctx);
+        for l in self.layers.iter_mut().filter(|l| l.active) {
+            let res = l.overlay.update(ctx, st, &mut self.state);
+            l.check("update", res);
         }
+        self.timing.update = start.elapsed();
+        self.report_status(st);
         Ok(())
     }
     fn render(&mut self, ctx: &context::Context, st: &mut state::State) -> Erm<()> {
+        let start = self.timing.start_render();
         ctx.clear_color(glam::Vec4::new(0.0, 0.0, 0.0, 0.0));
         ctx.clear();
-        for ov in self.overlays.iter_mut() {
-            ov.render(ctx, st, &mut self.state)?;
+        for l in self.layers.iter_mut().filter(|l| l.active) {
+            let res = l.overlay.render(ctx, st, &mut self.state);
+            l.check("render", res);
+            l.end_frame();
+        }
+        let line_height = self.state.assets.font.char_height as f32;
+        let mut y = ctx.render_height - line_height;
+        if let Some(d) = self.state.diagnostics() {
+            self.state.assets.font.render_text(ctx, st, &glam::Vec2::new(0.0, y), &d);
+            y -= line_height;
+        }
+        for l in self.layers.iter().filter(|l| l.active && l.is_faulted()) {
+            self.state.assets.font.render_text_parameterized(ctx, st,
+                &glam::Vec2::new(0.0, y),
+                &format!("! {}: {}", l.name, l.last_error.as_deref().unwrap_or("")),
+                font::BitmapParams {
+                    color: &[glam::Vec3::new(1.0, 0.3, 0.3)],
+                    scale: glam::Vec2::new(1.0, 1.0),
+                },
+            );
+            y -= line_height;
+        }
+        if let Some(limit) = self.cfg.fault_limit {
+            let broken: Vec<&'static str> = self.layers.iter()
+                .filter(|l| l.active && l.failures >= limit)
+                .map(|l| l.name)
+                .collect();
+            if !broken.is_empty() {
+                for nm in &broken {
+                    log::warn!("disabling overlay {} after {} consecutive failures", nm, limit);
+                }
+                let mut layers = self.active_layers();
+                layers.retain(|nm| !broken.contains(nm));
+                self.set_layers(ctx, &layers);
+                for l in self.layers.iter_mut().filter(|l| broken.contains(&l.name)) {
+                    l.failures = 0;
+                    l.last_error = None;
+                }
+            }
         }
+        self.timing.render = start.elapsed();
         Ok(())
     }
 }
@@ -2,24 +2,116 @@ use teleia::*;
 
 use glow::HasContext;
 use rand::Rng;
-use byteorder::{LE, ReadBytesExt};
+use newton_messages::{Message, SpawnAnchor, SpawnPlacement};
 
-use crate::overlay;
+use crate::{config, overlay};
 
-const SCALE: usize = 15;
-const WIDTH: usize = 1920 / SCALE;
-const HEIGHT: usize = 1080 / SCALE;
+const DEFAULT_GPU_SCALE: usize = 4; // screen pixels per cell
+const DEFAULT_CPU_SCALE: usize = 15;
+const READBACK_INTERVAL: u64 = 60; // ticks between copies of the GPU grid back to the CPU
+const DEFAULT_RULE: &str = "B3/S23";
+const LEADERBOARD_SIZE: usize = 5;
+const LEADERBOARD_INTERVAL: u64 = 60; // minimum ticks between leaderboard publishes
+const DEFAULT_SPAWN_OVERWRITE: f32 = 0.25; // fraction of a spawn's area that may already be taken
+pub const EVENTS: &[&[u8]] = &[b"overlay automata spawn"];
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    BadRule(String),
+}
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadRule(r) => write!(f, "bad automata rule: {}", r),
+        }
+    }
+}
+impl std::error::Error for Error {}
+
+/// An outer-totalistic rule: the live neighbor counts that bring a dead cell
+/// to life, and those that keep a live cell alive. Under Generations rules, with
+/// more than two states, cells that fail to survive spend states - 2 generations
+/// dying, neither counting as neighbors nor making room for births.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rule {
+    pub birth: [bool; 9],
+    pub survive: [bool; 9],
+    pub states: u8, // 2 for Life-like rules
+}
+impl Rule {
+    pub const LIFE: Self = Self {
+        birth: [false, false, false, true, false, false, false, false, false],
+        survive: [false, false, true, true, false, false, false, false, false],
+        states: 2,
+    };
+    /// Accepts "B36/S23" in either order and any case, or the older "23/36" (survival first).
+    /// A third part gives the number of states for Generations rules, as in
+    /// Brian's Brain, "B2/S/C3" or "/2/3".
+    /// Rules with B0 are rejected, as a cell born from nothing would have no owner.
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let bad = || Error::BadRule(s.to_owned());
+        let counts = |digits: &str| -> Result<[bool; 9], Error> {
+            let mut ret = [false; 9];
+            for c in digits.chars() {
+                let n = c.to_digit(10).filter(|n| *n <= 8).ok_or_else(bad)?;
+                ret[n as usize] = true;
+            }
+            Ok(ret)
+        };
+        let parts: Vec<&str> = s.trim().split('/').collect();
+        let (a, b, states) = match parts.as_slice() {
+            [a, b] => (*a, *b, 2),
+            [a, b, c] => {
+                let c = c.strip_prefix(['C', 'c']).unwrap_or(c);
+                (*a, *b, c.parse().ok().filter(|n| *n >= 2).ok_or_else(bad)?)
+            },
+            _ => return Err(bad()),
+        };
+        let tagged = |p: &str| p.chars().next().map(|c| c.to_ascii_uppercase());
+        let ret = match (tagged(a), tagged(b)) {
+            (Some('B'), Some('S')) => Self { birth: counts(&a[1..])?, survive: counts(&b[1..])?, states },
+            (Some('S'), Some('B')) => Self { birth: counts(&b[1..])?, survive: counts(&a[1..])?, states },
+            _ => Self { birth: counts(b)?, survive: counts(a)?, states },
+        };
+        if ret.birth[0] { return Err(bad()) }
+        Ok(ret)
+    }
+    /// The rule packed into a texel for the shaders: birth counts 0-7 as the bits of red,
+    /// survival counts 0-7 as the bits of green, the two counts of 8 in blue, and states in alpha.
+    fn texel(&self) -> [u8; 4] {
+        let bits = |counts: &[bool; 9]| (0..8).filter(|n| counts[*n]).fold(0u8, |acc, n| acc | 1 << n);
+        [
+            bits(&self.birth),
+            bits(&self.survive),
+            self.birth[8] as u8 | (self.survive[8] as u8) << 1,
+            self.states,
+        ]
+    }
+}
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let digits = |counts: &[bool; 9]| -> String {
+            (0..9).filter(|n| counts[*n]).map(|n| char::from(b'0' + n as u8)).collect()
+        };
+        write!(f, "B{}/S{}", digits(&self.birth), digits(&self.survive))?;
+        if self.states > 2 { write!(f, "/C{}", self.states)?; }
+        Ok(())
+    }
+}
 
 pub struct Pattern {
     w: usize, h: usize,
     cells: Vec<bool>,
+    rule: Option<Rule>, // from the RLE header, if it named one
 }
 impl Pattern {
-    pub fn from_rle(inp: &str) -> Option<Self> {
+    /// Patterns too big to fit a grid of width by height either way around are rejected.
+    pub fn from_rle(inp: &str, width: usize, height: usize) -> Option<Self> {
         let s = inp.replace(";", "\n");
         let mut data = String::new();
         let mut w = 0;
         let mut h = 0;
+        let mut rule = None;
         for line in s.split("\n") {
             if let Some('#') = line.trim().chars().nth(0) {
             } else if let Some('x') = line.trim().chars().nth(0) {
@@ -29,6 +121,8 @@ impl Pattern {
                             w = sval.trim().parse().ok()?;
                         } else if svar.trim() == "y" {
                             h = sval.trim().parse().ok()?;
+                        } else if svar.trim() == "rule" {
+                            rule = Some(Rule::parse(sval).ok()?);
                         }
                     }
                 }
@@ -36,14 +130,39 @@ impl Pattern {
                 data.push_str(&line);
             }
         }
-        if w == 0 || h == 0 || w > WIDTH || h > HEIGHT { return None }
+        if w == 0 || h == 0 || w.max(h) > width.max(height) || w.min(h) > width.min(height) { return None }
         let mut ret = Self {
             w, h,
             cells: vec![false; w * h],
+            rule,
         };
         ret.populate(&data);
         Some(ret)
     }
+    /// The pattern mirrored and then turned clockwise as the placement asks,
+    /// or None if it no longer fits on a grid of width by height.
+    pub fn placed(&self, p: &SpawnPlacement, width: usize, height: usize) -> Option<Self> {
+        let turns = p.rotate / 90;
+        let (w, h) = if turns % 2 == 1 { (self.h, self.w) } else { (self.w, self.h) };
+        if w > width || h > height { return None }
+        let mut ret = Self { w, h, cells: vec![false; w * h], rule: self.rule };
+        for y in 0..self.h as i32 {
+            for x in 0..self.w as i32 {
+                if !self.get(x, y) { continue }
+                let mut q = (
+                    if p.flip_x { self.w as i32 - 1 - x } else { x },
+                    if p.flip_y { self.h as i32 - 1 - y } else { y },
+                );
+                let (mut qw, mut qh) = (self.w as i32, self.h as i32);
+                for _ in 0..turns {
+                    q = (qh - 1 - q.1, q.0);
+                    (qw, qh) = (qh, qw);
+                }
+                ret.set(q.0, q.1);
+            }
+        }
+        Some(ret)
+    }
     pub fn idx(&self, x: i32, y: i32) -> usize{
         let ux = x.rem_euclid(self.w as i32) as usize;
         let uy = y.rem_euclid(self.h as i32) as usize;
@@ -91,28 +210,48 @@ impl Pattern {
     }
 }
 
-type Cell = u8;
+/// Owner 0 is an empty cell. Other owners index the color table; a live cell
+/// has age 0, and a dying one counts up the generations since it died.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct Cell {
+    owner: u8,
+    age: u8,
+}
+impl Cell {
+    const EMPTY: Self = Self { owner: 0, age: 0 };
+    fn alive(owner: u8) -> Self {
+        Self { owner, age: 0 }
+    }
+    fn is_alive(&self) -> bool {
+        self.owner > 0 && self.age == 0
+    }
+}
 
+#[derive(PartialEq)]
 struct CellRule {
     color: [u8; 4],
+    rule: Option<Rule>, // None follows the global rule
+    user: Option<String>, // who spawned the cells, if anyone said
 }
 
 struct CellBuffer {
-    buf: [Cell; WIDTH * HEIGHT],
+    w: usize, h: usize,
+    buf: Vec<Cell>,
 }
 impl CellBuffer {
-    pub fn new() -> Self {
+    pub fn new(w: usize, h: usize) -> Self {
         Self {
-            buf: [0; WIDTH * HEIGHT],
+            w, h,
+            buf: vec![Cell::EMPTY; w * h],
         }
     }
-    fn idx(x: i32, y: i32) -> usize {
-        let ux = x.rem_euclid(WIDTH as i32) as usize;
-        let uy = y.rem_euclid(HEIGHT as i32) as usize;
-        uy * WIDTH + ux
+    fn idx(&self, x: i32, y: i32) -> usize {
+        let ux = x.rem_euclid(self.w as i32) as usize;
+        let uy = y.rem_euclid(self.h as i32) as usize;
+        uy * self.w + ux
     }
     pub fn get(&self, x: i32, y: i32) -> Cell {
-        self.buf[Self::idx(x, y)]
+        self.buf[self.idx(x, y)]
     }
 
     pub fn neighbors(&self, x: i32, y: i32) -> [Cell; 8] {
@@ -127,16 +266,17 @@ impl CellBuffer {
         ]
     }
     pub fn is_nonzero(&self, x: i32, y: i32) -> bool {
-        self.get(x, y) > 0
+        self.get(x, y).is_alive()
     }
     pub fn count_cell(&self, x: i32, y: i32) -> i32 {
-        self.get(x, y).min(1) as i32
+        self.get(x, y).is_alive() as i32
     }
     pub fn count_neighbors(&self, x: i32, y: i32) -> i32 {
-        self.neighbors(x, y).into_iter().map(|c| c.min(1) as i32).sum()
+        self.neighbors(x, y).into_iter().filter(|c| c.is_alive()).count() as i32
     }
+    /// The owner of most live neighbors, or 0 if there are none.
     pub fn most_common_neighbor(&self, x: i32, y: i32) -> u8 {
-        let mut ns = self.neighbors(x, y);
+        let mut ns = self.neighbors(x, y).map(|c| if c.is_alive() { c.owner } else { 0 });
         ns.sort_unstable();
         let mut winner = 0;
         let mut score = 0;
@@ -151,146 +291,687 @@ impl CellBuffer {
         winner
     }
     pub fn set(&mut self, x: i32, y: i32, v: Cell) {
-        self.buf[Self::idx(x, y)] = v;
+        let idx = self.idx(x, y);
+        self.buf[idx] = v;
+    }
+    pub fn clear(&mut self) {
+        self.buf.fill(Cell::EMPTY);
+    }
+    pub fn population(&self) -> usize {
+        self.buf.iter().filter(|c| c.is_alive()).count()
+    }
+    /// Live cells per owner.
+    pub fn populations(&self) -> [usize; 256] {
+        let mut ret = [0; 256];
+        for c in self.buf.iter().filter(|c| c.is_alive()) { ret[c.owner as usize] += 1; }
+        ret
+    }
+    /// Owner in red and age in green, the layout both shaders read.
+    pub fn to_rgba(&self) -> Vec<u8> {
+        self.buf.iter().flat_map(|c| [c.owner, c.age, 0, 0xff]).collect()
+    }
+    /// The pieces of a w by h rectangle at (x, y) once wrapped around the grid edges,
+    /// as (x, y, w, h) within the grid.
+    pub fn wrapped(&self, x: i32, y: i32, w: usize, h: usize) -> Vec<(usize, usize, usize, usize)> {
+        let split = |p: i32, len: usize, size: usize| {
+            let p = p.rem_euclid(size as i32) as usize;
+            let first = len.min(size - p);
+            let mut ret = vec![(p, first)];
+            if len > first { ret.push((0, len - first)); }
+            ret
+        };
+        let ys = split(y, h, self.h);
+        split(x, w, self.w).into_iter()
+            .flat_map(|(x, w)| ys.iter().map(move |&(y, h)| (x, y, w, h)))
+            .collect()
+    }
+    /// to_rgba for a rectangle that lies within the grid.
+    pub fn rect_to_rgba(&self, x: usize, y: usize, w: usize, h: usize) -> Vec<u8> {
+        (y..y + h)
+            .flat_map(|uy| self.buf[uy * self.w + x..uy * self.w + x + w].iter())
+            .flat_map(|c| [c.owner, c.age, 0, 0xff])
+            .collect()
+    }
+    pub fn load_rgba(&mut self, pixels: &[u8]) {
+        for (c, px) in self.buf.iter_mut().zip(pixels.chunks_exact(4)) {
+            *c = Cell { owner: px[0], age: px[1] };
+        }
+    }
+    /// Write the next generation into next. Each live or dying cell follows its owner's rule;
+    /// a cell with enough neighbors to be born under the rule of the most common neighboring
+    /// owner goes to that owner, taking over live cells as well as empty ones.
+    /// The step shader does the same on the GPU.
+    pub fn step_into(&self, next: &mut CellBuffer, rule: impl Fn(u8) -> Rule) {
+        for ux in 0..self.w {
+            for uy in 0..self.h {
+                let x = ux as _; let y = uy as _;
+                let c = self.get(x, y);
+                if c.owner > 0 && !c.is_alive() {
+                    let age = c.age + 1;
+                    next.set(x, y, if age + 1 >= rule(c.owner).states { Cell::EMPTY } else { Cell { age, ..c } });
+                    continue;
+                }
+                let n = self.count_neighbors(x, y) as usize;
+                let w = if n > 0 { self.most_common_neighbor(x, y) } else { 0 };
+                if c.is_alive() && !rule(c.owner).survive[n] {
+                    next.set(x, y, if rule(c.owner).states > 2 { Cell { age: 1, ..c } } else { Cell::EMPTY })
+                } else if w > 0 && rule(w).birth[n] {
+                    next.set(x, y, Cell::alive(w))
+                } else {
+                    next.set(x, y, c)
+                }
+            }
+        }
+    }
+}
+
+/// The simulation stepped in a fragment shader, ping-ponging between two framebuffers
+/// that hold cells in the layout of CellBuffer::to_rgba.
+struct Gpu {
+    shader: shader::Shader,
+    fbs: [framebuffer::Framebuffer; 2],
+    front: usize, // which framebuffer holds the current generation
+    readback: glow::Buffer, // pixel pack buffer the current generation is copied into
+    pending: Option<glow::Fence>, // signaled once a copy into readback has finished
+}
+impl Gpu {
+    fn new(ctx: &context::Context, w: usize, h: usize) -> Self {
+        let shader = shader::Shader::new(
+            ctx,
+            include_str!("../assets/shaders/automata_step/vert.glsl"),
+            include_str!("../assets/shaders/automata_step/frag.glsl"),
+        );
+        shader.set_i32(ctx, "rules", 1);
+        let fbs = std::array::from_fn(|_| {
+            let fb = framebuffer::Framebuffer::new(ctx, &glam::Vec2::new(w as f32, h as f32), &glam::Vec2::ZERO);
+            unsafe {
+                fb.bind_texture(ctx);
+                ctx.gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MIN_FILTER, glow::NEAREST as _);
+                ctx.gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MAG_FILTER, glow::NEAREST as _);
+            }
+            fb
+        });
+        let readback = unsafe {
+            let buf = ctx.gl.create_buffer().expect("failed to create automata readback buffer");
+            ctx.gl.bind_buffer(glow::PIXEL_PACK_BUFFER, Some(buf));
+            ctx.gl.buffer_data_size(glow::PIXEL_PACK_BUFFER, (w * h * 4) as i32, glow::STREAM_READ);
+            ctx.gl.bind_buffer(glow::PIXEL_PACK_BUFFER, None);
+            buf
+        };
+        Self { shader, fbs, front: 0, readback, pending: None }
     }
 }
 
 pub struct Overlay {
     shader: shader::Shader,
-    tex: texture::Texture,
+    tex: texture::Texture, // the CPU grid, unused when stepping on the GPU
+    palette: texture::Texture, // colors by owner
+    rule_table: texture::Texture, // Rule::texel by owner
+    tables_dirty: bool,
+    gpu: Option<Gpu>,
+    width: usize,
+    height: usize,
     active: bool,
     buf0: CellBuffer,
     buf1: CellBuffer,
     next_rule: usize,
     rules: [CellRule; 256],
+    rule: Rule, // for owners whose pattern did not name one
+    rule_src: String, // the automata_rule parameter rule was parsed from
+    populations: [usize; 256], // live cells per owner as of the last step or readback, plus spawns since
+    published: Vec<(String, usize)>, // the leaderboard last sent over the bus
+    last_publish: u64,
 }
 impl Overlay {
-    pub fn new(ctx: &context::Context) -> Self {
+    pub fn new(ctx: &context::Context, cfg: &config::Config) -> Self {
         let rules = std::array::from_fn(|idx| match idx {
-            0 => CellRule { color: [0, 0, 0, 0] },
-            _ => CellRule { color: [0xff, 0xff, 0xff, 0xff] },
+            0 => CellRule { color: [0, 0, 0, 0], rule: None, user: None },
+            _ => CellRule { color: [0xff, 0xff, 0xff, 0xff], rule: None, user: None },
         });
-        Self {
-            shader: shader::Shader::new(
-                ctx,
-                include_str!("../assets/shaders/automata/vert.glsl"),
-                include_str!("../assets/shaders/automata/frag.glsl"),
-            ),
+        let default_scale = if cfg.automata_cpu { DEFAULT_CPU_SCALE } else { DEFAULT_GPU_SCALE };
+        let scale = cfg.automata_scale.unwrap_or(default_scale).max(1);
+        let width = (ctx.render_width as usize / scale).max(1);
+        let height = (ctx.render_height as usize / scale).max(1);
+        let shader = shader::Shader::new(
+            ctx,
+            include_str!("../assets/shaders/automata/vert.glsl"),
+            include_str!("../assets/shaders/automata/frag.glsl"),
+        );
+        shader.set_i32(ctx, "palette", 1);
+        shader.set_i32(ctx, "rules", 2);
+        let mut ret = Self {
+            shader,
             tex: texture::Texture::new_empty(ctx),
+            palette: texture::Texture::new_empty(ctx),
+            rule_table: texture::Texture::new_empty(ctx),
+            tables_dirty: true,
+            gpu: if cfg.automata_cpu { None } else { Some(Gpu::new(ctx, width, height)) },
+            width, height,
             active: false,
-            buf0: CellBuffer::new(),
-            buf1: CellBuffer::new(),
+            buf0: CellBuffer::new(width, height),
+            buf1: CellBuffer::new(width, height),
             next_rule: 1,
             rules,
+            rule: Rule::LIFE,
+            rule_src: DEFAULT_RULE.to_owned(),
+            populations: [0; 256],
+            published: Vec::new(),
+            last_publish: 0,
+        };
+        ret.flush(ctx);
+        ret.upload_tables(ctx);
+        ret
+    }
+    fn cur(&self) -> &CellBuffer {
+        if self.active { &self.buf0 } else { &self.buf1 }
+    }
+    fn cur_mut(&mut self) -> &mut CellBuffer {
+        if self.active { &mut self.buf0 } else { &mut self.buf1 }
+    }
+    /// The owner index for new cells: one the same user already has with the same rule and
+    /// color, or else the next one with no live cells, so that no existing territory changes
+    /// hands, color, or rule. The leaderboard adds up a user's indices.
+    fn owner_for(&mut self, rule: &CellRule) -> usize {
+        if rule.user.is_some() {
+            if let Some(i) = (1..256).find(|i| self.rules[*i] == *rule) { return i }
+        }
+        let i = (0..255)
+            .map(|k| (self.next_rule - 1 + k) % 255 + 1)
+            .find(|i| self.populations[*i] == 0)
+            .unwrap_or(self.next_rule);
+        self.next_rule = i % 255 + 1;
+        i
+    }
+    /// Live cells per user, most first.
+    fn standings(&self) -> Vec<(&str, usize, [u8; 4])> {
+        let mut ret: Vec<(&str, usize, [u8; 4])> = Vec::new();
+        for (r, pop) in self.rules.iter().zip(self.populations).filter(|(_, pop)| *pop > 0) {
+            let Some(user) = r.user.as_deref() else { continue };
+            match ret.iter_mut().find(|e| e.0 == user) {
+                Some(e) => e.1 += pop,
+                None => ret.push((user, pop, r.color)),
+            }
+        }
+        ret.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        ret
+    }
+    fn leaderboard(&self) -> Vec<(String, usize)> {
+        self.standings().into_iter()
+            .take(LEADERBOARD_SIZE)
+            .map(|(user, pop, _)| (user.to_owned(), pop))
+            .collect()
+    }
+    /// Send the leaderboard over the bus when it changes, at most once every LEADERBOARD_INTERVAL.
+    fn publish_leaderboard(&mut self, st: &state::State, ost: &mut overlay::State) {
+        // replays should not echo old leaderboards onto the bus
+        if ost.replaying || st.tick < self.last_publish + LEADERBOARD_INTERVAL { return }
+        let board = self.leaderboard();
+        if board == self.published { return }
+        let sexp = lexpr::Value::list(board.iter().map(|(user, pop)| lexpr::Value::cons(user.as_str(), *pop as u64)));
+        ost.publish(&Message::AutomataLeaderboard(sexp.to_string()));
+        self.published = board;
+        self.last_publish = st.tick;
+    }
+    /// The cell for a pattern's top left corner.
+    fn position(&self, ctx: &context::Context, ost: &overlay::State, p: &SpawnPlacement, pat: &Pattern) -> (i32, i32) {
+        let grid = glam::Vec2::new(self.width as f32, self.height as f32);
+        let dims = glam::Vec2::new(ctx.render_width, ctx.render_height);
+        let anchor = match p.anchor {
+            Some(SpawnAnchor::Emacs) => Some(glam::Vec2::new(ost.info.emacs_cursor.0, ost.info.emacs_cursor.1) / dims * grid),
+            Some(SpawnAnchor::Mouse) => Some(ost.input.pointer(dims) * grid),
+            None => None,
+        };
+        match (anchor, p.at) {
+            (Some(a), at) => {
+                let (dx, dy) = at.unwrap_or((0, 0));
+                (a.x.floor() as i32 - pat.w as i32 / 2 + dx, a.y.floor() as i32 - pat.h as i32 / 2 + dy)
+            },
+            (None, Some(at)) => at,
+            (None, None) => {
+                let mut rng = rand::thread_rng();
+                (rng.gen_range(0..self.width) as i32, rng.gen_range(0..self.height) as i32)
+            },
         }
     }
-    pub fn spawn(&mut self, x: i32, y: i32, c: Cell, pat: &Pattern) {
-        let cur = if self.active { &mut self.buf0 } else { &mut self.buf1 };
+    /// Live cells belonging to anyone but user that spawning pat at (x, y) would replace.
+    fn overwritten(&self, x: i32, y: i32, pat: &Pattern, user: Option<&str>) -> usize {
+        let cur = self.cur();
+        let mut ret = 0;
+        for yoff in 0..pat.h as i32 {
+            for xoff in 0..pat.w as i32 {
+                let c = cur.get(x + xoff, y + yoff);
+                if c.is_alive() && (user.is_none() || self.rules[c.owner as usize].user.as_deref() != user) {
+                    ret += 1;
+                }
+            }
+        }
+        ret
+    }
+    pub fn spawn(&mut self, x: i32, y: i32, c: u8, pat: &Pattern) {
+        let mut pops = self.populations;
+        let cur = self.cur_mut();
         for uxoff in 0..pat.w {
             for uyoff in 0..pat.h {
                 let xoff = uxoff as i32; let yoff = uyoff as i32;
-                cur.set(x + xoff, y + yoff, if pat.get(xoff, yoff) { c } else { 0 });
+                let old = cur.get(x + xoff, y + yoff);
+                if old.is_alive() { pops[old.owner as usize] -= 1; }
+                let new = if pat.get(xoff, yoff) { Cell::alive(c) } else { Cell::EMPTY };
+                if new.is_alive() { pops[c as usize] += 1; }
+                cur.set(x + xoff, y + yoff, new);
             }
         }
+        self.populations = pops;
     }
+    /// Advance one generation.
     pub fn step(&mut self) {
         let (cur, next) = if self.active {
             (&mut self.buf0, &mut self.buf1)
         } else {
             (&mut self.buf1, &mut self.buf0)
         };
-        for ux in 0..WIDTH {
-            for uy in 0..HEIGHT {
-                let x = ux as _; let y = uy as _;
-                let n = cur.count_neighbors(x, y);
-                if cur.is_nonzero(x, y) && n != 2 && n != 3{
-                    next.set(x, y, 0)
-                } else if n == 3 {
-                    next.set(x, y, cur.most_common_neighbor(x, y))
-                } else {
-                    next.set(x, y, cur.get(x, y))
-                }
-            }
-        }
+        let (rules, global) = (&self.rules, self.rule);
+        cur.step_into(next, |owner| rules[owner as usize].rule.unwrap_or(global));
         self.active = !self.active;
     }
-    pub fn upload(&self, ctx: &context::Context) {
-        let cur = if self.active { &self.buf0 } else { &self.buf1 };
-        let mut buf = vec![0; WIDTH * HEIGHT * 4];
-        for (idx, c) in cur.buf.iter().enumerate() {
-            for off in 0..4 { buf[idx * 4 + off] = self.rules[*c as usize].color[off] }
-        }
+    /// Advance one generation on the GPU, into the back framebuffer.
+    fn step_gpu(&mut self, ctx: &context::Context, st: &mut state::State) {
+        let Some(gpu) = &mut self.gpu else { return };
+        st.bind_framebuffer(ctx, &gpu.fbs[1 - gpu.front]);
+        st.bind_2d(ctx, &gpu.shader);
+        gpu.fbs[gpu.front].bind_texture(ctx);
+        self.rule_table.bind_index(ctx, 1);
+        gpu.shader.set_position_2d(
+            ctx, st,
+            &glam::Vec2::new(0.0, 0.0),
+            &glam::Vec2::new(self.width as f32, self.height as f32),
+        );
+        st.mesh_square.render(ctx);
+        st.bind_render_framebuffer(ctx);
+        gpu.front = 1 - gpu.front;
+    }
+    /// Start copying the current generation from the GPU into the readback buffer,
+    /// unless a copy is already under way. finish_readback picks it up once it is done.
+    fn start_readback(&mut self, ctx: &context::Context, st: &mut state::State) {
+        let Some(gpu) = &mut self.gpu else { return };
+        if gpu.pending.is_some() { return }
+        st.bind_framebuffer(ctx, &gpu.fbs[gpu.front]);
         unsafe {
-            self.tex.bind(ctx);
-            ctx.gl.tex_image_2d(
-                glow::TEXTURE_2D,
-                0,
-                glow::RGBA as i32,
-                WIDTH as i32,
-                HEIGHT as i32,
-                0,
+            ctx.gl.bind_buffer(glow::PIXEL_PACK_BUFFER, Some(gpu.readback));
+            ctx.gl.read_pixels(
+                0, 0,
+                self.width as i32,
+                self.height as i32,
                 glow::RGBA,
                 glow::UNSIGNED_BYTE,
-                Some(&buf),
+                glow::PixelPackData::BufferOffset(0),
             );
-            ctx.gl.generate_mipmap(glow::TEXTURE_2D);
+            ctx.gl.bind_buffer(glow::PIXEL_PACK_BUFFER, None);
+            match ctx.gl.fence_sync(glow::SYNC_GPU_COMMANDS_COMPLETE, 0) {
+                Ok(f) => gpu.pending = Some(f),
+                Err(e) => log::warn!("failed to fence automata readback: {}", e),
+            }
         }
+        st.bind_render_framebuffer(ctx);
     }
-}
-impl overlay::Overlay for Overlay {
-    fn reset(&mut self, ctx: &context::Context, st: &mut state::State, _ost: &mut overlay::State) -> Erm<()> {
-        let cur = if self.active { &mut self.buf0 } else { &mut self.buf1 };
-        for ux in 0..WIDTH {
-            for uy in 0..HEIGHT {
-                cur.set(ux as i32, uy as i32, 0)
+    /// Load a finished readback into the CPU grid, for spawns and the leaderboard.
+    /// Does nothing while the copy is still in flight, so the CPU grid lags the GPU
+    /// by up to READBACK_INTERVAL ticks and a frame or two.
+    fn finish_readback(&mut self, ctx: &context::Context) {
+        let Some(gpu) = &mut self.gpu else { return };
+        let Some(fence) = gpu.pending else { return };
+        let len = self.width * self.height * 4;
+        let mut pixels = vec![0; len];
+        unsafe {
+            let status = ctx.gl.client_wait_sync(fence, 0, 0);
+            if status != glow::ALREADY_SIGNALED && status != glow::CONDITION_SATISFIED { return }
+            ctx.gl.delete_sync(fence);
+            gpu.pending = None;
+            ctx.gl.bind_buffer(glow::PIXEL_PACK_BUFFER, Some(gpu.readback));
+            let mapped = ctx.gl.map_buffer_range(glow::PIXEL_PACK_BUFFER, 0, len as i32, glow::MAP_READ_BIT);
+            if mapped.is_null() {
+                log::warn!("failed to map automata readback buffer");
+            } else {
+                pixels.copy_from_slice(std::slice::from_raw_parts(mapped, len));
+                ctx.gl.unmap_buffer(glow::PIXEL_PACK_BUFFER);
+            }
+            ctx.gl.bind_buffer(glow::PIXEL_PACK_BUFFER, None);
+            if mapped.is_null() { return }
+        }
+        self.cur_mut().load_rgba(&pixels);
+        self.populations = self.cur().populations();
+    }
+    /// Throw away a readback in flight, which would not include changes made since it started.
+    fn cancel_readback(&mut self, ctx: &context::Context) {
+        let Some(gpu) = &mut self.gpu else { return };
+        if let Some(fence) = gpu.pending.take() {
+            unsafe { ctx.gl.delete_sync(fence); }
+        }
+    }
+    /// Send the CPU grid to wherever the current generation is drawn from.
+    fn flush(&self, ctx: &context::Context) {
+        let pixels = self.cur().to_rgba();
+        unsafe {
+            match &self.gpu {
+                Some(gpu) => {
+                    gpu.fbs[gpu.front].bind_texture(ctx);
+                    ctx.gl.tex_sub_image_2d(
+                        glow::TEXTURE_2D,
+                        0,
+                        0, 0,
+                        self.width as i32,
+                        self.height as i32,
+                        glow::RGBA,
+                        glow::UNSIGNED_BYTE,
+                        glow::PixelUnpackData::Slice(&pixels),
+                    );
+                },
+                None => {
+                    self.tex.bind(ctx);
+                    ctx.gl.tex_image_2d(
+                        glow::TEXTURE_2D,
+                        0,
+                        glow::RGBA as i32,
+                        self.width as i32,
+                        self.height as i32,
+                        0,
+                        glow::RGBA,
+                        glow::UNSIGNED_BYTE,
+                        Some(&pixels),
+                    );
+                    ctx.gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MIN_FILTER, glow::NEAREST as _);
+                    ctx.gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MAG_FILTER, glow::NEAREST as _);
+                },
+            }
+        }
+    }
+    /// Send a w by h rectangle of the CPU grid at (x, y) to wherever the current generation
+    /// is drawn from, wrapping around the edges.
+    fn flush_rect(&self, ctx: &context::Context, x: i32, y: i32, w: usize, h: usize) {
+        match &self.gpu {
+            Some(gpu) => gpu.fbs[gpu.front].bind_texture(ctx),
+            None => self.tex.bind(ctx),
+        }
+        for (rx, ry, rw, rh) in self.cur().wrapped(x, y, w, h) {
+            let pixels = self.cur().rect_to_rgba(rx, ry, rw, rh);
+            unsafe {
+                ctx.gl.tex_sub_image_2d(
+                    glow::TEXTURE_2D,
+                    0,
+                    rx as i32, ry as i32,
+                    rw as i32,
+                    rh as i32,
+                    glow::RGBA,
+                    glow::UNSIGNED_BYTE,
+                    glow::PixelUnpackData::Slice(&pixels),
+                );
             }
         }
+    }
+    /// Upload the per-owner colors and rules, if they changed.
+    fn upload_tables(&mut self, ctx: &context::Context) {
+        if !self.tables_dirty { return }
+        let colors: Vec<u8> = self.rules.iter().flat_map(|r| r.color).collect();
+        let rules: Vec<u8> = self.rules.iter().flat_map(|r| r.rule.unwrap_or(self.rule).texel()).collect();
+        for (tex, data) in [(&self.palette, &colors), (&self.rule_table, &rules)] {
+            unsafe {
+                tex.bind(ctx);
+                ctx.gl.tex_image_2d(
+                    glow::TEXTURE_2D,
+                    0,
+                    glow::RGBA as i32,
+                    256,
+                    1,
+                    0,
+                    glow::RGBA,
+                    glow::UNSIGNED_BYTE,
+                    Some(data),
+                );
+                ctx.gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MIN_FILTER, glow::NEAREST as _);
+                ctx.gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MAG_FILTER, glow::NEAREST as _);
+            }
+        }
+        self.tables_dirty = false;
+    }
+}
+impl overlay::Overlay for Overlay {
+    fn events(&self) -> &'static [&'static [u8]] {
+        EVENTS
+    }
+    fn reset(&mut self, ctx: &context::Context, _st: &mut state::State, _ost: &mut overlay::State) -> Erm<()> {
+        self.cancel_readback(ctx);
+        self.cur_mut().clear();
+        self.flush(ctx);
+        self.populations = [0; 256];
         Ok(())
     }
-    fn handle_binary(
-        &mut self, ctx: &context::Context, st: &mut state::State, _ost: &mut overlay::State,
-        msg: &fig::BinaryMessage,
+    fn handle_message(
+        &mut self, ctx: &context::Context, st: &mut state::State, ost: &mut overlay::State,
+        msg: &Message,
     ) -> Erm<()> {
-        if msg.event == b"overlay automata spawn" {
-            let res: Erm<()> = (|| {
-                let mut reader = std::io::Cursor::new(&msg.data);
-                let rle = fig::read_length_prefixed_utf8(&mut reader)?;
-                let _user = fig::read_length_prefixed_utf8(&mut reader)?;
-                let col = reader.read_u32::<LE>()?;
-                let r = (col >> 16 & 0xff) as u8;
-                let g = (col >> 8 & 0xff) as u8;
-                let b = (col & 0xff) as u8;
-                if let Some(pat) = Pattern::from_rle(&rle) {
-                    let mut rng = rand::thread_rng();
-                    let x = rng.gen_range(0..WIDTH);
-                    let y = rng.gen_range(0..HEIGHT);
-                    self.rules[self.next_rule] = CellRule { color: [r, g, b, 0xff] };
-                    self.spawn(x as i32, y as i32, self.next_rule as u8, &pat);
-                    self.next_rule = (self.next_rule + 1) % 256;
-                    if self.next_rule == 0 { self.next_rule = 1; }
+        if let Message::AutomataSpawn(spawn) = msg {
+            let r = (spawn.color >> 16 & 0xff) as u8;
+            let g = (spawn.color >> 8 & 0xff) as u8;
+            let b = (spawn.color & 0xff) as u8;
+            let (w, h) = (self.width, self.height);
+            if let Some(pat) = Pattern::from_rle(&spawn.rle, w, h).and_then(|p| p.placed(&spawn.placement, w, h)) {
+                // spawns are checked against the CPU grid, which on the GPU is the last readback,
+                // and only the stamped cells are uploaded
+                let (x, y) = self.position(ctx, ost, &spawn.placement, &pat);
+                let user = Some(spawn.user.as_str()).filter(|u| !u.is_empty());
+                let limit = ost.params.f32(st, "automata_spawn_overwrite", DEFAULT_SPAWN_OVERWRITE);
+                let over = self.overwritten(x, y, &pat, user);
+                if over as f32 > limit * (pat.w * pat.h) as f32 {
+                    log::info!("rejected automata spawn from {}: it would overwrite {} live cells", spawn.user, over);
+                    return Ok(());
                 }
-                Ok(())
-            })();
-            if let Err(e) = res { log::warn!("malformed automata spawn update: {}", e); }
+                let rule = CellRule { color: [r, g, b, 0xff], rule: pat.rule, user: user.map(|u| u.to_owned()) };
+                let owner = self.owner_for(&rule);
+                self.rules[owner] = rule;
+                self.tables_dirty = true;
+                self.cancel_readback(ctx);
+                self.spawn(x, y, owner as u8, &pat);
+                self.flush_rect(ctx, x, y, pat.w, pat.h);
+            }
         }
         Ok(())
     }
-    fn update(&mut self, ctx: &context::Context, st: &mut state::State, _ost: &mut overlay::State) -> Erm<()> {
-        if st.tick % 10 == 0 {
-            self.step();
-            self.upload(ctx);
+    fn update(&mut self, ctx: &context::Context, st: &mut state::State, ost: &mut overlay::State) -> Erm<()> {
+        let src = ost.params.string(st, "automata_rule", DEFAULT_RULE);
+        if src != self.rule_src {
+            match Rule::parse(&src) {
+                Ok(r) => { self.rule = r; self.tables_dirty = true; },
+                Err(e) => log::warn!("{}", e),
+            }
+            self.rule_src = src;
+        }
+        self.upload_tables(ctx);
+        let default_rate = if self.gpu.is_some() { 1 } else { 10 };
+        let rate = ost.params.i64(st, "automata_step_ticks", default_rate).max(1) as u64;
+        if st.tick % rate == 0 {
+            if self.gpu.is_some() {
+                self.step_gpu(ctx, st);
+            } else {
+                self.step();
+                self.flush(ctx);
+                self.populations = self.cur().populations();
+            }
+        }
+        if self.gpu.is_some() {
+            self.finish_readback(ctx);
+            if st.tick % READBACK_INTERVAL == 0 { self.start_readback(ctx, st); }
         }
+        self.publish_leaderboard(st, ost);
         Ok(())
     }
-    fn render(&mut self, ctx: &context::Context, st: &mut state::State, _ost: &mut overlay::State) -> Erm<()> {
+    fn render(&mut self, ctx: &context::Context, st: &mut state::State, ost: &mut overlay::State) -> Erm<()> {
         st.bind_2d(ctx, &self.shader);
-        self.tex.bind(ctx);
+        match &self.gpu {
+            Some(gpu) => gpu.fbs[gpu.front].bind_texture(ctx),
+            None => self.tex.bind(ctx),
+        }
+        self.palette.bind_index(ctx, 1);
+        self.rule_table.bind_index(ctx, 2);
         self.shader.set_position_2d(
             ctx, st,
             &glam::Vec2::new(0.0, 0.0),
-            &glam::Vec2::new(1920.0, 1080.0)
+            &glam::Vec2::new(ctx.render_width, ctx.render_height)
         );
         st.mesh_square.render(ctx);
+        // top owners down the right edge, each in their own color
+        for (i, (user, pop, color)) in self.standings().into_iter().take(LEADERBOARD_SIZE).enumerate() {
+            let text = format!("{} {}", user, pop);
+            let width = text.chars().count() as f32 * ost.assets.font.char_width as f32;
+            ost.assets.font.render_text_parameterized(ctx, st,
+                &glam::Vec2::new(ctx.render_width - width, i as f32 * ost.assets.font.char_height as f32),
+                &text,
+                font::BitmapParams {
+                    color: &[glam::Vec3::new(color[0] as f32, color[1] as f32, color[2] as f32) / 255.0],
+                    scale: glam::Vec2::new(1.0, 1.0),
+                },
+            );
+        }
         Ok(())
     }
+    fn status(&self) -> Vec<(&'static str, lexpr::Value)> {
+        vec![
+            ("population", lexpr::Value::from(self.cur().population() as u64)),
+            ("owners", lexpr::Value::from(self.standings().len() as u64)),
+            ("rule", lexpr::Value::from(self.rule.to_string())),
+            ("backend", lexpr::Value::from(if self.gpu.is_some() { "gpu" } else { "cpu" })),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid(w: usize, h: usize, live: &[(i32, i32)]) -> CellBuffer {
+        let mut ret = CellBuffer::new(w, h);
+        for (x, y) in live { ret.set(*x, *y, Cell::alive(1)); }
+        ret
+    }
+    fn live(buf: &CellBuffer) -> Vec<(i32, i32)> {
+        let mut ret: Vec<(i32, i32)> = (0..buf.h as i32)
+            .flat_map(|y| (0..buf.w as i32).map(move |x| (x, y)))
+            .filter(|(x, y)| buf.is_nonzero(*x, *y))
+            .collect();
+        ret.sort_unstable();
+        ret
+    }
+    fn run(mut buf: CellBuffer, rule: Rule, steps: usize) -> CellBuffer {
+        let mut next = CellBuffer::new(buf.w, buf.h);
+        for _ in 0..steps {
+            buf.step_into(&mut next, |_| rule);
+            std::mem::swap(&mut buf, &mut next);
+        }
+        buf
+    }
+    fn cells(pat: &Pattern) -> Vec<(i32, i32)> {
+        let mut ret: Vec<(i32, i32)> = (0..pat.h as i32)
+            .flat_map(|y| (0..pat.w as i32).map(move |x| (x, y)))
+            .filter(|(x, y)| pat.get(*x, *y))
+            .collect();
+        ret.sort_unstable();
+        ret
+    }
+
+    #[test]
+    fn blinker() {
+        let horizontal = grid(5, 5, &[(1, 2), (2, 2), (3, 2)]);
+        let mut vertical = vec![(2, 1), (2, 2), (2, 3)];
+        vertical.sort_unstable();
+        assert_eq!(live(&run(horizontal, Rule::LIFE, 1)), vertical);
+        let horizontal = grid(5, 5, &[(1, 2), (2, 2), (3, 2)]);
+        assert_eq!(live(&run(horizontal, Rule::LIFE, 2)), vec![(1, 2), (2, 2), (3, 2)]);
+    }
+
+    #[test]
+    fn glider() {
+        let start = [(1, 0), (2, 1), (0, 2), (1, 2), (2, 2)];
+        let mut moved: Vec<(i32, i32)> = start.iter().map(|(x, y)| (x + 1, y + 1)).collect();
+        moved.sort_unstable();
+        let after = run(grid(8, 8, &start), Rule::LIFE, 4);
+        assert_eq!(live(&after), moved);
+        assert!(after.buf.iter().all(|c| c.age == 0));
+    }
+
+    #[test]
+    fn brians_brain_dying() {
+        let brain = Rule::parse("/2/3").unwrap();
+        let once = run(grid(6, 6, &[(2, 2)]), brain, 1);
+        assert_eq!(once.get(2, 2), Cell { owner: 1, age: 1 });
+        assert_eq!(once.population(), 0);
+        let twice = run(grid(6, 6, &[(2, 2)]), brain, 2);
+        assert!(twice.buf.iter().all(|c| *c == Cell::EMPTY));
+        // dying cells are not neighbors, so two of them bring nothing to life
+        let mut dying = CellBuffer::new(6, 6);
+        dying.set(2, 2, Cell { owner: 1, age: 1 });
+        dying.set(3, 2, Cell { owner: 1, age: 1 });
+        assert!(run(dying, brain, 1).buf.iter().all(|c| *c == Cell::EMPTY));
+    }
+
+    #[test]
+    fn rule_strings() {
+        for s in ["B3/S23", "B36/S23", "B2/S/C3", "B/S012345678", "B3/S23/C4"] {
+            let rule = Rule::parse(s).unwrap();
+            assert_eq!(rule.to_string(), s);
+            assert_eq!(Rule::parse(&rule.to_string()).unwrap(), rule);
+        }
+        assert_eq!(Rule::parse("23/3").unwrap(), Rule::LIFE);
+        assert_eq!(Rule::parse("s23/b3").unwrap(), Rule::LIFE);
+        assert_eq!(Rule::parse(DEFAULT_RULE).unwrap(), Rule::LIFE);
+        let brain = Rule::parse("/2/3").unwrap();
+        assert_eq!(brain, Rule::parse("B2/S/C3").unwrap());
+        assert_eq!(brain.to_string(), "B2/S/C3");
+        for s in ["B03/S23", "B9/S23", "B3", "B3/S23/C1", "B3/S23/C", "3/2/1/0"] {
+            assert!(Rule::parse(s).is_err(), "{s} should not parse");
+        }
+    }
+
+    #[test]
+    fn placed() {
+        // o.
+        // o.
+        // oo
+        let l = Pattern::from_rle("x = 2, y = 3\no$o$2o!", 10, 10).unwrap();
+        assert_eq!(cells(&l), vec![(0, 0), (0, 1), (0, 2), (1, 2)]);
+        let place = |rotate, flip_x, flip_y| SpawnPlacement { rotate, flip_x, flip_y, ..Default::default() };
+        // ooo
+        // o..
+        let turned = l.placed(&place(90, false, false), 10, 10).unwrap();
+        assert_eq!((turned.w, turned.h), (3, 2));
+        assert_eq!(cells(&turned), vec![(0, 0), (0, 1), (1, 0), (2, 0)]);
+        // oo
+        // .o
+        // .o
+        let half = l.placed(&place(180, false, false), 10, 10).unwrap();
+        assert_eq!(cells(&half), vec![(0, 0), (1, 0), (1, 1), (1, 2)]);
+        // .o
+        // .o
+        // oo
+        let mirrored = l.placed(&place(0, true, false), 10, 10).unwrap();
+        assert_eq!(cells(&mirrored), vec![(0, 2), (1, 0), (1, 1), (1, 2)]);
+        // flips come before the turn
+        // o..
+        // ooo
+        let both = l.placed(&place(90, true, false), 10, 10).unwrap();
+        assert_eq!(cells(&both), vec![(0, 0), (0, 1), (1, 1), (2, 1)]);
+        assert_eq!(cells(&l.placed(&place(180, true, true), 10, 10).unwrap()), cells(&l));
+        assert!(l.placed(&place(90, false, false), 2, 3).is_none());
+    }
+
+    #[test]
+    fn wrapped_rects() {
+        let buf = grid(8, 6, &[(7, 5), (0, 0)]);
+        assert_eq!(buf.wrapped(2, 1, 3, 2), vec![(2, 1, 3, 2)]);
+        assert_eq!(buf.wrapped(-1, -1, 3, 2), vec![(7, 5, 1, 1), (7, 0, 1, 1), (0, 5, 2, 1), (0, 0, 2, 1)]);
+        assert_eq!(buf.wrapped(14, 3, 2, 3), vec![(6, 3, 2, 3)]);
+        assert_eq!(buf.rect_to_rgba(7, 5, 1, 1), vec![1, 0, 0, 0xff]);
+        assert_eq!(buf.rect_to_rgba(0, 0, 2, 1), vec![1, 0, 0, 0xff, 0, 0, 0, 0xff]);
+        let cells: usize = buf.wrapped(-1, -1, 3, 2).into_iter()
+            .map(|(x, y, w, h)| buf.rect_to_rgba(x, y, w, h).chunks(4).filter(|px| px[0] > 0).count())
+            .sum();
+        assert_eq!(cells, 2);
+    }
 }
@@ -1,16 +1,184 @@
 use teleia::*;
 
 use glow::HasContext;
+use newton_messages::{BrushMode, Message};
 
 use crate::{input, overlay};
 
-pub const SCALE: usize = 4;
-pub const WIDTH: usize = 1920 / SCALE;
-pub const HEIGHT: usize = 1080 / SCALE;
+// Strokes are kept in drawing coordinates, which span REF_WIDTH by REF_HEIGHT
+// whatever the canvas size, so they survive resizes and mean the same on any screen.
+pub const REF_WIDTH: f32 = 1920.0;
+pub const REF_HEIGHT: f32 = 1080.0;
+
+// Each canvas pixel is an index into the palette texture, plus a coverage
+// byte that anti-aliases stroke edges against whatever is underneath.
+pub const EMPTY: u8 = 0;
+pub const REVEAL: u8 = 1;
+pub const INK: u8 = 2; // index of the first ink color
+pub const PALETTE: &[[u8; 4]] = &[
+    [0xff, 0xff, 0xff, 0xff],
+    [0x00, 0x00, 0x00, 0xff],
+    [0xe8, 0x3b, 0x3b, 0xff],
+    [0xf5, 0x9e, 0x2b, 0xff],
+    [0xf7, 0xe2, 0x4a, 0xff],
+    [0x4c, 0xc9, 0x5a, 0xff],
+    [0x3d, 0xd6, 0xe0, 0xff],
+    [0x3b, 0x6c, 0xe8, 0xff],
+    [0xc8, 0x4b, 0xe0, 0xff],
+];
+pub const SIZES: &[i32] = &[1, 2, 4, 8]; // brush radii cycled through by hotkey
+pub const MAX_SIZE: i32 = 32;
+pub const BRUSH_SCALE: f32 = 4.0; // drawing units per unit of brush size
+pub const THIN_SPEED: f32 = 40.0; // pen speed, in drawing units per tick, at which strokes are half as wide
+pub const THINNEST: f32 = 0.35; // fraction of the brush size that fast strokes thin down to
+pub const WIDTH_SMOOTHING: f32 = 0.3; // how quickly the width follows the pen speed
+pub const CURVE_STEP: f32 = 2.0; // pixels between samples along a smoothed segment
+pub const MAX_HISTORY: usize = 256; // older operations are baked into the base layer, unless they will fade
+pub const DISSOLVE_STEPS: u64 = 8; // fading strokes are redrawn this many times as they dissolve
+pub const EXPORT_REVEAL: [u8; 4] = [0xff, 0xff, 0xff, 0x80]; // how revealed pixels look in exported images
+pub const SNAPSHOTS_KEY: &str = "drawing:snapshots";
+pub const SNAPSHOT_PREFIX: &str = "drawing:snapshot:"; // followed by milliseconds since the epoch
+pub const EVENTS: &[&[u8]] = &[
+    b"overlay drawing brush",
+    b"overlay drawing stroke",
+    b"overlay drawing clear",
+    b"overlay drawing undo",
+    b"overlay drawing redo",
+    b"overlay drawing export",
+    b"overlay drawing import",
+];
+
+#[derive(Debug, Clone)]
+pub struct Stroke {
+    pub val: u8,
+    pub size: i32,
+    pub points: Vec<glam::Vec2>, // in drawing coordinates
+    pub radii: Vec<f32>, // per point, in drawing units
+    pub time: u64, // tick of the most recent point
+    pub dissolved: f32, // how far it had dissolved when last drawn
+}
+impl Stroke {
+    pub fn new(val: u8, size: i32, time: u64) -> Self {
+        Self { val, size, points: Vec::new(), radii: Vec::new(), time, dissolved: 0.0 }
+    }
+    /// Add a point. Points are sampled once a tick, so the distance from the
+    /// previous one is the pen speed, and faster strokes are drawn thinner.
+    pub fn push(&mut self, p: glam::Vec2) {
+        let full = self.size as f32 * BRUSH_SCALE;
+        let r = match (self.points.last(), self.radii.last()) {
+            (Some(last), Some(r)) => {
+                let speed = (p - *last).length();
+                let target = full * (1.0 / (1.0 + speed / THIN_SPEED)).max(THINNEST);
+                r + (target - r) * WIDTH_SMOOTHING
+            },
+            _ => full,
+        };
+        self.points.push(p);
+        self.radii.push(r);
+    }
+}
+
+/// The center of a pixel.
+fn center((x, y): (i32, i32)) -> glam::Vec2 {
+    glam::Vec2::new(x as f32 + 0.5, y as f32 + 0.5)
+}
+
+fn catmull_rom(p0: glam::Vec2, p1: glam::Vec2, p2: glam::Vec2, p3: glam::Vec2, t: f32) -> glam::Vec2 {
+    let (t2, t3) = (t * t, t * t * t);
+    0.5 * (2.0 * p1
+        + (p2 - p0) * t
+        + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t2
+        + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t3)
+}
+
+/// An image set directly onto the canvas, stretched to cover it.
+#[derive(Debug, Clone)]
+pub struct Stamp {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Option<u8>>, // None leaves the canvas alone
+    pub time: u64,
+    pub dissolved: f32, // how far it had dissolved when last drawn
+}
+
+/// One undoable change to the canvas.
+#[derive(Debug, Clone)]
+pub enum Op {
+    Stroke(Stroke),
+    Stamp(Stamp),
+    Clear,
+}
+impl Op {
+    /// When the operation was last touched, for operations that fade.
+    pub fn time(&self) -> Option<u64> {
+        match self {
+            Self::Stroke(s) => Some(s.time),
+            Self::Stamp(s) => Some(s.time),
+            Self::Clear => None,
+        }
+    }
+    pub fn dissolved(&self) -> f32 {
+        match self {
+            Self::Stroke(s) => s.dissolved,
+            Self::Stamp(s) => s.dissolved,
+            Self::Clear => 0.0,
+        }
+    }
+    pub fn set_dissolved(&mut self, f: f32) {
+        match self {
+            Self::Stroke(s) => s.dissolved = f,
+            Self::Stamp(s) => s.dissolved = f,
+            Self::Clear => {},
+        }
+    }
+}
+
+/// A region of the canvas in pixels, from min inclusive to max exclusive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rect {
+    pub min: glam::IVec2,
+    pub max: glam::IVec2,
+}
+impl Rect {
+    pub fn union(&self, o: &Self) -> Self {
+        Self { min: self.min.min(o.min), max: self.max.max(o.max) }
+    }
+    pub fn intersects(&self, o: &Self) -> bool {
+        self.min.cmplt(o.max).all() && o.min.cmplt(self.max).all()
+    }
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.min.x && y >= self.min.y && x < self.max.x && y < self.max.y
+    }
+}
+
+/// A stable pseudo-random value in [0, 1) per pixel, used to dissolve strokes.
+fn dissolve_threshold(x: i32, y: i32) -> f32 {
+    let mut h = (x as u32).wrapping_mul(0x9e3779b1) ^ (y as u32).wrapping_mul(0x85ebca77);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0xc2b2ae3d);
+    h ^= h >> 13;
+    (h & 0xffff) as f32 / 65536.0
+}
+
 pub struct Overlay {
     pub tex: texture::Texture,
-    pub pixels: [u8; WIDTH * HEIGHT],
-    pub last_point: Option<(i32, i32)>,
+    pub palette: texture::Texture,
+    pub width: usize, // canvas size, following the window
+    pub height: usize,
+    pub pixels: Vec<[u8; 2]>, // palette index and coverage
+    pub base: Vec<[u8; 2]>, // operations too old to undo
+    pub dirty: bool, // pixels changed since the last upload
+    pub history: Vec<Op>,
+    pub redo: Vec<Op>,
+    pub lifetime: u64, // ticks before a stroke starts to fade, or 0 to keep strokes forever
+    pub fade: u64, // ticks a stroke takes to dissolve
+    pub dissolve: f32, // fraction of pixels skipped by set, for strokes that are fading
+    pub next_fade: u64, // tick at which the next operation dissolves a step further
+    pub clip: Option<Rect>, // the only pixels drawn to while redrawing part of the canvas
+    pub last_point: Option<glam::Vec2>,
+    pub mode: BrushMode,
+    pub color: usize, // index into PALETTE
+    pub size: i32,
     pub shader_white: shader::Shader,
     pub shader_background: shader::Shader,
 }
@@ -22,97 +190,518 @@ impl Overlay {
             include_str!("../assets/shaders/background/frag.glsl"),
         );
         shader_background.set_i32(ctx, "background", 1);
-        Self {
+        shader_background.set_i32(ctx, "palette", 2);
+        let (width, height) = ((ctx.render_width as usize).max(1), (ctx.render_height as usize).max(1));
+        let ret = Self {
             tex: texture::Texture::new_empty(ctx),
-            pixels: [0; WIDTH * HEIGHT],
+            palette: texture::Texture::new_empty(ctx),
+            width,
+            height,
+            pixels: vec![[EMPTY, 0]; width * height],
+            base: vec![[EMPTY, 0]; width * height],
+            dirty: true,
+            history: Vec::new(),
+            redo: Vec::new(),
+            lifetime: 0,
+            fade: 0,
+            dissolve: 0.0,
+            next_fade: u64::MAX,
+            clip: None,
             last_point: None,
+            mode: BrushMode::Reveal,
+            color: 0,
+            size: 1,
             shader_white: shader::Shader::new(
                 ctx,
                 include_str!("../assets/shaders/white/vert.glsl"),
                 include_str!("../assets/shaders/white/frag.glsl"),
             ),
             shader_background,
+        };
+        ret.upload_palette(ctx);
+        ret
+    }
+    /// The pixel value a brush paints with.
+    pub fn value(mode: BrushMode, color: usize) -> u8 {
+        match mode {
+            BrushMode::Reveal => REVEAL,
+            BrushMode::Ink => INK + color as u8,
+            BrushMode::Eraser => EMPTY,
+        }
+    }
+    pub fn brush(&self) -> u8 {
+        Self::value(self.mode, self.color)
+    }
+    pub fn set_brush(&mut self, b: &newton_messages::DrawingBrush) {
+        if let Some(m) = b.mode { self.mode = m; }
+        if let Some(c) = b.color {
+            if (c as usize) < PALETTE.len() {
+                self.color = c as usize;
+            } else {
+                log::warn!("drawing palette has no color {}", c);
+            }
         }
+        if let Some(s) = b.size { self.size = (s as i32).clamp(1, MAX_SIZE); }
     }
     pub fn coord(&self, x: usize, y: usize) -> Option<usize> {
-        if x >= WIDTH || y >= HEIGHT {
+        if x >= self.width || y >= self.height {
             None
         } else {
-            Some(x + y * WIDTH)
+            Some(x + y * self.width)
         }
     }
+    pub fn canvas(&self) -> Rect {
+        Rect { min: glam::IVec2::ZERO, max: glam::IVec2::new(self.width as i32, self.height as i32) }
+    }
+    /// Canvas pixels per drawing unit.
+    pub fn scale(&self) -> glam::Vec2 {
+        glam::Vec2::new(self.width as f32 / REF_WIDTH, self.height as f32 / REF_HEIGHT)
+    }
+    /// Match the canvas to a new window size, stretching what was baked into the base layer.
+    pub fn resize(&mut self, st: &state::State, width: usize, height: usize) {
+        let mut base = vec![[EMPTY, 0]; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                base[x + y * width] = self.base[x * self.width / width + y * self.height / height * self.width];
+            }
+        }
+        self.base = base;
+        self.pixels = vec![[EMPTY, 0]; width * height];
+        self.width = width;
+        self.height = height;
+        self.rebuild(st);
+    }
+    /// Paint val over a pixel with coverage in [0, 1]. Edges blend with the empty canvas;
+    /// where strokes of different values meet, the one covering more than half wins.
+    pub fn paint(&mut self, val: u8, coverage: f32, x: i32, y: i32) {
+        if self.dissolve > 0.0 && dissolve_threshold(x, y) < self.dissolve { return }
+        if self.clip.is_some_and(|c| !c.contains(x, y)) { return }
+        let Some(idx) = self.coord(x as usize, y as usize) else { return };
+        let c = (coverage.clamp(0.0, 1.0) * 255.0).round() as u8;
+        let [old, oc] = self.pixels[idx];
+        self.pixels[idx] = if val == EMPTY {
+            let left = oc.min(255 - c);
+            if left == 0 { [EMPTY, 0] } else { [old, left] }
+        } else if old == val || old == EMPTY || c >= 0x80 {
+            [val, oc.max(c)]
+        } else {
+            [old, oc]
+        };
+        self.dirty = true;
+    }
     pub fn set(&mut self, val: u8, x: i32, y: i32) {
-        self.coord(x as usize, y as usize).map(|idx| self.pixels[idx] = val);
-    }
-    pub fn point(&mut self, val: u8, x: i32, y: i32) {
-        self.set(val, x, y - 1);
-        self.set(val, x - 1, y);
-        self.set(val, x, y);
-        self.set(val, x + 1, y);
-        self.set(val, x, y + 1);
-    }
-    pub fn line(&mut self, val: u8, (mut x0, mut y0): (i32, i32), (x1, y1): (i32, i32)) {
-        let dx = (x1 - x0).abs();
-        let sx = if x0 < x1 { 1 } else { -1 };
-        let dy = -((y1 - y0).abs());
-        let sy = if y0 < y1 { 1 } else { -1 };
-        let mut error = dx + dy;
-        loop {
-            self.point(val, x0, y0);
-            let e2 = 2 * error;
-            if e2 >= dy {
-                if x0 == x1 { break; }
-                error += dy;
-                x0 += sx;
+        self.paint(val, 1.0, x, y);
+    }
+    /// An anti-aliased capsule joining two circles, given as center and radius.
+    pub fn capsule(&mut self, val: u8, (a, ra): (glam::Vec2, f32), (b, rb): (glam::Vec2, f32)) {
+        let r = ra.max(rb) + 1.0;
+        let mut lo = (a.min(b) - r).floor().max(glam::Vec2::ZERO);
+        let mut hi = (a.max(b) + r).ceil().min(glam::Vec2::new(self.width as f32, self.height as f32));
+        if let Some(c) = self.clip {
+            lo = lo.max(c.min.as_vec2());
+            hi = hi.min(c.max.as_vec2());
+        }
+        let ab = b - a;
+        let len2 = ab.length_squared();
+        for y in lo.y as i32..hi.y as i32 {
+            for x in lo.x as i32..hi.x as i32 {
+                let p = center((x, y));
+                let t = if len2 > 0.0 { ((p - a).dot(ab) / len2).clamp(0.0, 1.0) } else { 0.0 };
+                let d = (p - (a + ab * t)).length();
+                let coverage = ra + (rb - ra) * t - d + 0.5;
+                if coverage > 0.0 { self.paint(val, coverage, x, y); }
             }
-            if e2 <= dx {
-                if y0 == y1 { break; }
-                error += dx;
-                y0 += sy;
+        }
+    }
+    /// Centers and radii in canvas pixels along the part of a stroke between points
+    /// i and i + 1, smoothed as a Catmull-Rom spline through the neighbouring points.
+    fn curve(&self, s: &Stroke, i: usize) -> Vec<(glam::Vec2, f32)> {
+        let n = s.points.len();
+        if i + 1 >= n { return Vec::new() }
+        let scale = self.scale();
+        let at = |j: usize| s.points[j.min(n - 1)] * scale;
+        let (p0, p1, p2, p3) = (at(i.saturating_sub(1)), at(i), at(i + 1), at(i + 2));
+        let (r1, r2) = (s.radii[i] * scale.min_element(), s.radii[i + 1] * scale.min_element());
+        let steps = ((p2 - p1).length() / CURVE_STEP).ceil().max(1.0) as usize;
+        (0..=steps)
+            .map(|k| {
+                let t = k as f32 / steps as f32;
+                (catmull_rom(p0, p1, p2, p3, t), r1 + (r2 - r1) * t)
+            })
+            .collect()
+    }
+    pub fn segment(&mut self, s: &Stroke, i: usize) {
+        for w in self.curve(s, i).windows(2) { self.capsule(s.val, w[0], w[1]); }
+    }
+    pub fn stroke(&mut self, s: &Stroke) {
+        if let [p] = s.points.as_slice() {
+            let c = (*p * self.scale(), s.radii[0] * self.scale().min_element());
+            self.capsule(s.val, c, c);
+        }
+        for i in 0..s.points.len().saturating_sub(1) { self.segment(s, i); }
+    }
+    /// The pixels an operation can paint, or None for one that touches nothing.
+    pub fn bounds(&self, op: &Op) -> Option<Rect> {
+        let canvas = self.canvas();
+        let s = match op {
+            Op::Stroke(s) => s,
+            Op::Stamp(_) | Op::Clear => return Some(canvas),
+        };
+        let mut samples = match s.points.as_slice() {
+            [p] => vec![(*p * self.scale(), s.radii[0] * self.scale().min_element())],
+            _ => Vec::new(),
+        };
+        for i in 0..s.points.len().saturating_sub(1) { samples.extend(self.curve(s, i)); }
+        // the same margin capsule draws within
+        samples.into_iter()
+            .map(|(p, r)| Rect {
+                min: (p - (r + 1.0)).floor().as_ivec2(),
+                max: (p + (r + 1.0)).ceil().as_ivec2(),
+            })
+            .reduce(|a, b| a.union(&b))
+            .filter(|r| r.intersects(&canvas))
+    }
+    pub fn apply(&mut self, op: &Op) {
+        match op {
+            Op::Stroke(s) => self.stroke(s),
+            Op::Stamp(s) => {
+                let c = self.clip.unwrap_or(self.canvas());
+                for y in c.min.y.max(0) as usize..(c.max.y as usize).min(self.height) {
+                    for x in c.min.x.max(0) as usize..(c.max.x as usize).min(self.width) {
+                        let src = x * s.width / self.width + y * s.height / self.height * s.width;
+                        if let Some(v) = s.pixels[src] { self.set(v, x as i32, y as i32); }
+                    }
+                }
+            },
+            Op::Clear => {
+                self.pixels.fill([EMPTY, 0]);
+                self.dirty = true;
+            },
+        }
+    }
+    /// Start a new undoable operation, which discards anything that could be redone.
+    pub fn push(&mut self, op: Op) {
+        self.redo.clear();
+        self.next_fade = self.next_fade.min(self.fade_tick(&op));
+        self.history.push(op);
+        if self.history.len() > MAX_HISTORY {
+            // nothing fades once it is in the base layer, so operations that will fade stay
+            // in history until they expire; bake up to the oldest one that will not
+            let lifetime = self.lifetime;
+            let Some(i) = self.history.iter().position(|op| lifetime == 0 || op.time().is_none()) else { return };
+            let old: Vec<Op> = self.history.drain(..=i).collect();
+            std::mem::swap(&mut self.pixels, &mut self.base);
+            for op in &old { self.apply(op); }
+            std::mem::swap(&mut self.pixels, &mut self.base);
+        }
+    }
+    pub fn begin_stroke(&mut self, st: &state::State, p: glam::Vec2) {
+        let mut s = Stroke::new(self.brush(), self.size, st.tick);
+        s.push(p);
+        self.stroke(&s);
+        self.push(Op::Stroke(s));
+    }
+    /// Add a point to the stroke in progress. The segment before the previous point
+    /// is drawn now that its curve is known; the last one waits for end_stroke.
+    pub fn extend_stroke(&mut self, st: &state::State, p: glam::Vec2) {
+        let mut history = std::mem::take(&mut self.history);
+        if let Some(Op::Stroke(s)) = history.last_mut() {
+            s.push(p);
+            s.time = st.tick;
+            if s.points.len() >= 3 { self.segment(s, s.points.len() - 3); }
+        }
+        self.history = history;
+    }
+    /// Finish the local stroke in progress, if any.
+    pub fn end_stroke(&mut self) {
+        if self.last_point.take().is_none() { return }
+        let history = std::mem::take(&mut self.history);
+        if let Some(Op::Stroke(s)) = history.last() { self.segment(s, s.points.len().saturating_sub(2)); }
+        self.history = history;
+    }
+    /// Draw a whole stroke at once, e.g. one sent over the bus.
+    pub fn add_stroke(&mut self, s: Stroke) {
+        self.end_stroke(); // so a local stroke in progress does not continue this one
+        self.stroke(&s);
+        self.push(Op::Stroke(s));
+    }
+    fn remote_stroke(&mut self, st: &state::State, r: &newton_messages::DrawingStroke) {
+        let color = r.brush.color.map(|c| c as usize).unwrap_or(self.color);
+        if color >= PALETTE.len() {
+            log::warn!("drawing palette has no color {}", color);
+            return;
+        }
+        // giving a color implies ink, as for the hotkey
+        let mode = r.brush.mode.unwrap_or(if r.brush.color.is_some() { BrushMode::Ink } else { self.mode });
+        let size = r.brush.size.map(|s| (s as i32).clamp(1, MAX_SIZE)).unwrap_or(self.size);
+        let mut s = Stroke::new(Self::value(mode, color), size, st.tick);
+        for (x, y) in &r.points { s.push(glam::Vec2::new(*x as f32, *y as f32)); }
+        self.add_stroke(s);
+    }
+    pub fn clear(&mut self) {
+        self.end_stroke();
+        self.apply(&Op::Clear);
+        self.push(Op::Clear);
+    }
+    pub fn undo(&mut self, st: &state::State) {
+        self.end_stroke();
+        if let Some(op) = self.history.pop() {
+            self.redo.push(op);
+            self.rebuild(st);
+        }
+    }
+    pub fn redo(&mut self, st: &state::State) {
+        self.end_stroke();
+        if let Some(op) = self.redo.pop() {
+            self.history.push(op);
+            self.rebuild(st);
+        }
+    }
+    /// How far an operation has dissolved, from 0 (intact) to 1 (gone), in steps of
+    /// 1 / DISSOLVE_STEPS so that the canvas only needs redrawing when a step is crossed.
+    fn faded(&self, st: &state::State, op: &Op) -> f32 {
+        let Some(time) = op.time().filter(|_| self.lifetime > 0) else { return 0.0 };
+        let age = st.tick.saturating_sub(time);
+        if age <= self.lifetime { return 0.0 }
+        let steps = (age - self.lifetime) * DISSOLVE_STEPS / self.fade.max(1);
+        steps.min(DISSOLVE_STEPS) as f32 / DISSOLVE_STEPS as f32
+    }
+    /// The tick at which an operation will have dissolved a step further than it was drawn.
+    fn fade_tick(&self, op: &Op) -> u64 {
+        let Some(time) = op.time().filter(|_| self.lifetime > 0) else { return u64::MAX };
+        let step = (op.dissolved() * DISSOLVE_STEPS as f32).round() as u64 + 1;
+        time.saturating_add(self.lifetime).saturating_add((step * self.fade.max(1)).div_ceil(DISSOLVE_STEPS))
+    }
+    /// Drop operations that have fully dissolved, and redraw the parts of the canvas
+    /// under those that are gone or have dissolved further. Nothing is looked at
+    /// until next_fade, when the first of them is due.
+    fn expire(&mut self, st: &state::State) {
+        if st.tick < self.next_fade { return }
+        let mut changed: Option<Rect> = None;
+        let mut history = std::mem::take(&mut self.history);
+        for op in history.iter_mut() {
+            let f = self.faded(st, op);
+            if f != op.dissolved() {
+                if let Some(b) = self.bounds(op) { changed = Some(changed.map_or(b, |c| c.union(&b))); }
+                op.set_dissolved(f);
             }
         }
+        history.retain(|op| op.dissolved() < 1.0);
+        self.next_fade = history.iter().map(|op| self.fade_tick(op)).min().unwrap_or(u64::MAX);
+        self.history = history;
+        if let Some(r) = changed { self.redraw(st, r); }
+    }
+    /// Redraw part of the canvas from the base layer and history,
+    /// skipping operations that do not touch it.
+    pub fn redraw(&mut self, st: &state::State, region: Rect) {
+        let start = self.history.iter().rposition(|op| matches!(op, Op::Clear));
+        let canvas = self.canvas();
+        let r = Rect { min: region.min.max(canvas.min), max: region.max.min(canvas.max) };
+        if !r.intersects(&canvas) { return }
+        for y in r.min.y as usize..r.max.y as usize {
+            let row = y * self.width;
+            let (lo, hi) = (row + r.min.x as usize, row + r.max.x as usize);
+            if start.is_some() { self.pixels[lo..hi].fill([EMPTY, 0]); } else { self.pixels[lo..hi].copy_from_slice(&self.base[lo..hi]); }
+        }
+        self.dirty = true;
+        self.clip = Some(r);
+        let mut history = std::mem::take(&mut self.history);
+        for op in history[start.map(|i| i + 1).unwrap_or(0)..].iter_mut() {
+            op.set_dissolved(self.faded(st, op));
+            if !self.bounds(op).is_some_and(|b| b.intersects(&r)) { continue }
+            self.dissolve = op.dissolved();
+            self.apply(op);
+        }
+        self.dissolve = 0.0;
+        self.clip = None;
+        self.history = history;
+    }
+    /// Redraw the whole canvas from the base layer and history.
+    pub fn rebuild(&mut self, st: &state::State) {
+        self.redraw(st, self.canvas());
+        self.next_fade = self.history.iter().map(|op| self.fade_tick(op)).min().unwrap_or(u64::MAX);
+    }
+    /// The canvas as a PNG, with revealed areas drawn translucent white.
+    pub fn export_png(&self) -> Erm<Vec<u8>> {
+        let mut img = image::RgbaImage::new(self.width as u32, self.height as u32);
+        for (i, [v, coverage]) in self.pixels.iter().enumerate() {
+            let c = match *v {
+                EMPTY => [0; 4],
+                REVEAL if *coverage >= 0x80 => EXPORT_REVEAL,
+                REVEAL => [0; 4],
+                v => PALETTE.get((v - INK) as usize)
+                    .map(|c| [c[0], c[1], c[2], *coverage])
+                    .unwrap_or([0; 4]),
+            };
+            img.put_pixel((i % self.width) as u32, (i / self.width) as u32, image::Rgba(c));
+        }
+        let mut ret = Vec::new();
+        img.write_to(&mut std::io::Cursor::new(&mut ret), image::ImageFormat::Png)?;
+        Ok(ret)
+    }
+    /// Use an image as a stencil: transparent pixels leave the canvas alone, exact
+    /// palette colors become that ink, and anything else takes the current brush.
+    /// Exported canvases import back as they were. Images larger than the canvas are
+    /// rejected from their header, before anything is decoded.
+    pub fn import_png(&self, st: &state::State, bytes: &[u8]) -> Erm<Stamp> {
+        let reader = image::ImageReader::with_format(std::io::Cursor::new(bytes), image::ImageFormat::Png);
+        let (w, h) = reader.into_dimensions()?;
+        if w as usize > self.width || h as usize > self.height {
+            return Err(format!("{}x{} image is larger than the {}x{} canvas", w, h, self.width, self.height).into());
+        }
+        let img = image::load_from_memory_with_format(bytes, image::ImageFormat::Png)?.to_rgba8();
+        let brush = self.brush();
+        let pixels = img.pixels()
+            .map(|p| {
+                if p.0[3] < 0x80 { return None }
+                Some(if p.0 == EXPORT_REVEAL { REVEAL } else {
+                    PALETTE.iter().position(|c| c[..3] == p.0[..3]).map(|i| INK + i as u8).unwrap_or(brush)
+                })
+            })
+            .collect();
+        Ok(Stamp { width: img.width() as usize, height: img.height() as usize, pixels, time: st.tick, dissolved: 0.0 })
+    }
+    /// Upload the canvas to Redis under a timestamped key, listed in SNAPSHOTS_KEY.
+    fn export(&self, ost: &mut overlay::State) -> Erm<()> {
+        let png = self.export_png()?;
+        let millis = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_millis();
+        let key = format!("{}{}", SNAPSHOT_PREFIX, millis);
+        let mut set = redis::cmd("SET");
+        set.arg(&key).arg(png);
+        let mut lpush = redis::cmd("LPUSH");
+        lpush.arg(SNAPSHOTS_KEY).arg(&key);
+        ost.redis_write(&[set, lpush])?;
+        log::info!("saved drawing as {}", key);
+        Ok(())
     }
-    pub fn upload(&self, ctx: &context::Context) {
+    /// Stamp a snapshot saved by export back onto the canvas, given its Redis key.
+    fn import(&mut self, st: &state::State, ost: &mut overlay::State, key: &str) -> Erm<()> {
+        if !key.starts_with(SNAPSHOT_PREFIX) { return Err(format!("{} is not a drawing snapshot", key).into()) }
+        let conn = ost.redis.get().ok_or("Redis is unavailable")?;
+        let bytes: Option<Vec<u8>> = redis::cmd("GET").arg(key).query(conn)?;
+        let bytes = bytes.ok_or_else(|| format!("no drawing at {}", key))?;
+        let stamp = Op::Stamp(self.import_png(st, &bytes)?);
+        self.end_stroke();
+        self.apply(&stamp);
+        self.push(stamp);
+        Ok(())
+    }
+    fn upload_palette(&self, ctx: &context::Context) {
+        let mut entries = [0; 256 * 4]; // EMPTY and REVEAL are transparent
+        for (i, c) in PALETTE.iter().enumerate() {
+            let idx = (INK as usize + i) * 4;
+            entries[idx..idx + 4].copy_from_slice(c);
+        }
+        unsafe {
+            self.palette.bind(ctx);
+            ctx.gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MIN_FILTER, glow::NEAREST as _);
+            ctx.gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MAG_FILTER, glow::NEAREST as _);
+            ctx.gl.tex_image_2d(
+                glow::TEXTURE_2D,
+                0,
+                glow::RGBA as i32,
+                256,
+                1,
+                0,
+                glow::RGBA,
+                glow::UNSIGNED_BYTE,
+                Some(&entries),
+            );
+        }
+    }
+    pub fn upload(&mut self, ctx: &context::Context) {
+        if !self.dirty { return }
+        self.dirty = false;
         unsafe {
             let err = ctx.gl.get_error();
             self.tex.bind(ctx);
+            // pixels are palette indices, so they must never be blended
+            ctx.gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MIN_FILTER, glow::NEAREST as _);
+            ctx.gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MAG_FILTER, glow::NEAREST as _);
+            // rows are two bytes a pixel, so odd widths are not 4-byte aligned
+            ctx.gl.pixel_store_i32(glow::UNPACK_ALIGNMENT, 1);
             ctx.gl.tex_image_2d(
                 glow::TEXTURE_2D,
                 0,
-                glow::R8 as i32,
-                WIDTH as i32,
-                HEIGHT as i32,
+                glow::RG8 as i32,
+                self.width as i32,
+                self.height as i32,
                 0,
-                glow::RED,
+                glow::RG,
                 glow::UNSIGNED_BYTE,
-                Some(&self.pixels),
+                Some(self.pixels.as_flattened()),
             );
-            ctx.gl.generate_mipmap(glow::TEXTURE_2D);
+            ctx.gl.pixel_store_i32(glow::UNPACK_ALIGNMENT, 4);
         }
     }
 }
 impl overlay::Overlay for Overlay {
-    fn update(&mut self, ctx: &context::Context, st: &mut state::State, ost: &mut overlay::State) -> Erm<()> {
-        match ost.input.get_command() {
-            input::Command::Drawing => {
-                let (sx, sy) = ost.input.get_mouse();
-                let x = sx / (SCALE as i32);
-                let y = sy / (SCALE as i32);
-                if let Some(last) = self.last_point {
-                    self.line(1, last, (x, y));
-                } else {
-                    self.point(1, x, y);
-                }
-                self.last_point = Some((x, y));
-            },
-            input::Command::EraseAll => {
-                self.pixels.fill(0);
-                self.last_point = None;
+    fn events(&self) -> &'static [&'static [u8]] {
+        EVENTS
+    }
+    fn handle_message(&mut self, ctx: &context::Context, st: &mut state::State, ost: &mut overlay::State, msg: &Message) -> Erm<()> {
+        match msg {
+            Message::DrawingBrush(b) => self.set_brush(b),
+            Message::DrawingStroke(s) => self.remote_stroke(st, s),
+            Message::DrawingClear => self.clear(),
+            Message::DrawingUndo => self.undo(st),
+            Message::DrawingRedo => self.redo(st),
+            Message::DrawingExport => if let Err(e) = self.export(ost) {
+                log::warn!("failed to export drawing: {}", e);
             },
-            input::Command::None => {
-                self.last_point = None;
+            Message::DrawingImport(source) => if let Err(e) = self.import(st, ost, source) {
+                log::warn!("failed to import drawing from {}: {}", source, e);
             },
+            _ => {},
+        }
+        Ok(())
+    }
+    fn update(&mut self, ctx: &context::Context, st: &mut state::State, ost: &mut overlay::State) -> Erm<()> {
+        let (width, height) = (ctx.render_width as usize, ctx.render_height as usize);
+        if width > 0 && height > 0 && (width, height) != (self.width, self.height) {
+            self.resize(st, width, height);
+        }
+        let lifetime = (ost.params.f32(st, "drawing_lifetime", 0.0).max(0.0) * 60.0) as u64;
+        let fade = (ost.params.f32(st, "drawing_fade", 2.0).max(0.0) * 60.0) as u64;
+        if (lifetime, fade) != (self.lifetime, self.fade) {
+            // every operation may now be due to dissolve at a different time
+            (self.lifetime, self.fade) = (lifetime, fade);
+            self.next_fade = 0;
         }
+        for cmd in ost.input.pressed() {
+            match cmd {
+                input::Command::CycleColor => {
+                    self.color = (self.color + 1) % PALETTE.len();
+                    self.mode = BrushMode::Ink;
+                },
+                input::Command::CycleSize => {
+                    let next = SIZES.iter().position(|s| *s == self.size).map(|i| i + 1).unwrap_or(0);
+                    self.size = SIZES[next % SIZES.len()];
+                },
+                input::Command::Brush(m) => self.mode = *m,
+                input::Command::Undo => self.undo(st),
+                input::Command::Redo => self.redo(st),
+                input::Command::Export => if let Err(e) = self.export(ost) {
+                    log::warn!("failed to export drawing: {}", e);
+                },
+                _ => {},
+            }
+        }
+        if ost.input.was_pressed(&input::Command::EraseAll) {
+            self.clear();
+        } else if ost.input.is_held(&input::Command::Drawing) {
+            let dims = glam::Vec2::new(ctx.render_width, ctx.render_height);
+            let p = ost.input.pointer(dims) * glam::Vec2::new(REF_WIDTH, REF_HEIGHT);
+            match self.last_point {
+                Some(last) if last == p => {},
+                Some(_) => self.extend_stroke(st, p),
+                None => self.begin_stroke(st, p),
+            }
+            self.last_point = Some(p);
+        } else {
+            self.end_stroke();
+        }
+        self.expire(st);
         self.upload(ctx);
         Ok(())
     }
@@ -120,12 +709,21 @@ impl overlay::Overlay for Overlay {
         st.bind_2d(ctx, &self.shader_background);
         self.tex.bind(ctx);
         ost.backgrounds.drawing.bind_index(ctx, 1);
+        self.palette.bind_index(ctx, 2);
         self.shader_background.set_position_2d(
             ctx, st,
             &glam::Vec2::new(0.0, 0.0),
-            &glam::Vec2::new(1920.0, 1080.0)
+            &glam::Vec2::new(ctx.render_width, ctx.render_height)
         );
         st.mesh_square.render(ctx);
         Ok(())
     }
+    fn status(&self) -> Vec<(&'static str, lexpr::Value)> {
+        vec![
+            ("mode", lexpr::Value::from(self.mode.to_string())),
+            ("color", lexpr::Value::from(self.color as u64)),
+            ("size", lexpr::Value::from(self.size as u64)),
+            ("history", lexpr::Value::from(self.history.len() as u64)),
+        ]
+    }
 }
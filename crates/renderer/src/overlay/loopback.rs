@@ -4,7 +4,7 @@ use std::{io::{Read, Write}, process};
 use byteorder::WriteBytesExt;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 
-use crate::overlay;
+use crate::{config, overlay};
 
 const SEGMENT_LENGTH: f32 = 4.8;
 
@@ -39,13 +39,14 @@ fn ffmpeg_to_adts(sample_rate: u32, samples: &[f32]) -> Option<Vec<u8>> {
     Some(ret)
 }
 
-fn upload_sample(conn: &mut redis::Connection, sequence: u32, sample_rate: u32, sample: &[f32]) {
-    let max: f32 = *sample.iter().max_by(|x, y| f32::total_cmp(x, y)).unwrap();
+fn upload_sample(conn: &mut redis::Connection, sequence: u32, sample_rate: u32, sample: &[f32]) -> Erm<()> {
+    let max: f32 = sample.iter().copied().max_by(f32::total_cmp).unwrap_or(0.0);
     let cells = (max / 0.1) as usize;
-    let adts = ffmpeg_to_adts(sample_rate, sample).unwrap();
-    let _: () = conn.lpush("hlssamples", adts).unwrap();
-    let _: () = conn.ltrim("hlssamples", 0, 10).unwrap();
-    let _: () = conn.set("hlssequence", sequence).unwrap();
+    let adts = ffmpeg_to_adts(sample_rate, sample).ok_or("failed to encode sample with ffmpeg")?;
+    let _: () = conn.lpush("hlssamples", adts)?;
+    let _: () = conn.ltrim("hlssamples", 0, 10)?;
+    let _: () = conn.set("hlssequence", sequence)?;
+    Ok(())
 }
 
 pub struct Overlay {
@@ -53,24 +54,27 @@ pub struct Overlay {
 }
 
 impl Overlay {
-    pub fn new(ctx: &context::Context) -> Self {
-        let redis = redis::Client::open("redis://shiro").unwrap();
-        let mut redis_conn = redis.get_connection().unwrap();
+    pub fn new(ctx: &context::Context, cfg: &config::Config) -> Erm<Self> {
+        let redis_failed = |e: redis::RedisError| config::Error::ConnectFailed("Redis", cfg.redis.clone(), e.to_string());
+        let redis = redis::Client::open(&*cfg.redis).map_err(redis_failed)?;
+        let mut redis_conn = redis.get_connection().map_err(redis_failed)?;
         let host = cpal::default_host();
-        let device = host.default_input_device().unwrap();
-        let config = device.default_input_config().unwrap();
+        let device = host.default_input_device().ok_or("no default input device")?;
+        let config = device.default_input_config()?;
         let sample_rate = config.sample_rate().0;
         let mut buf: Vec<f32> = Vec::new();
         let mut sequence = 0;
-        let _: () = redis_conn.del("hlssamples").unwrap();
-        let _: () = redis_conn.set("hlssequence", 0).unwrap();
+        let _: () = redis_conn.del("hlssamples")?;
+        let _: () = redis_conn.set("hlssequence", 0)?;
         let stream = device.build_input_stream(
             &config.into(),
             move |samples: &[f32], info| {
                 buf.extend_from_slice(samples);
                 let upload_size = (SEGMENT_LENGTH * 2.0 * sample_rate as f32) as usize;
                 if buf.len() > upload_size {
-                    upload_sample(&mut redis_conn, sequence, sample_rate, &buf[0..upload_size]);
+                    if let Err(e) = upload_sample(&mut redis_conn, sequence, sample_rate, &buf[0..upload_size]) {
+                        log::warn!("failed to upload audio sample: {}", e);
+                    }
                     buf.drain(0..upload_size);
                     sequence += 1;
                 }
@@ -79,11 +83,11 @@ impl Overlay {
                 println!("error: {}", err);
             },
             None,
-        ).unwrap();
-        stream.play().unwrap();
-        Self {
+        )?;
+        stream.play()?;
+        Ok(Self {
             stream,
-        }
+        })
     }
 }
 
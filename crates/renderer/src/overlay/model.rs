@@ -2,8 +2,11 @@ use teleia::*;
 use termion::raw::IntoRawMode;
 
 use std::f32::consts::PI;
+use newton_messages::Message;
 
-use crate::{overlay, terminal};
+use crate::{config, overlay, terminal};
+
+pub const EVENTS: &[&[u8]] = &[b"overlay avatar text"];
 
 pub struct Terminal {
     ost: overlay::State,
@@ -12,9 +15,9 @@ pub struct Terminal {
     model_fb: framebuffer::Framebuffer,
 }
 impl Terminal {
-    pub fn new(ctx: &context::Context) -> Self {
+    pub fn new(ctx: &context::Context, cfg: &config::Config) -> Self {
         Self {
-            ost: overlay::State::new(ctx),
+            ost: overlay::State::new(ctx, cfg),
             output: std::io::stdout().into_raw_mode().expect("failed to set raw mode"),
             terminal: terminal::Terminal::new(ctx, 64, 64),
             model_fb: framebuffer::Framebuffer::new(
@@ -81,12 +84,12 @@ impl Overlay {
 }
 
 impl overlay::Overlay for Overlay {
-    fn handle_binary(&mut self, ctx: &context::Context, st: &mut state::State, ost: &mut overlay::State, msg: &fig::BinaryMessage) -> Erm<()> {
-        if msg.event == b"overlay avatar text" {
-            match str::from_utf8(&msg.data) {
-                Ok(s) => self.terminal.fill_string(s),
-                Err(e) => log::warn!("malformed avatar text update: {}", e),
-            }
+    fn events(&self) -> &'static [&'static [u8]] {
+        EVENTS
+    }
+    fn handle_message(&mut self, ctx: &context::Context, st: &mut state::State, ost: &mut overlay::State, msg: &Message) -> Erm<()> {
+        if let Message::AvatarText(s) = msg {
+            self.terminal.fill_string(s);
         }
         Ok(())
     }
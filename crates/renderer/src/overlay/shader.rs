@@ -1,7 +1,11 @@
 use teleia::*;
 
+use newton_messages::Message;
+
 use crate::{overlay, toggle};
 
+pub const EVENTS: &[&[u8]] = &[b"overlay shader"];
+
 pub struct Overlay {
     visualizer: newton_shader::Visualizer,
 }
@@ -16,25 +20,21 @@ impl Overlay {
 }
 
 impl overlay::Overlay for Overlay {
+    fn events(&self) -> &'static [&'static [u8]] {
+        EVENTS
+    }
     fn reset(&mut self, ctx: &context::Context, st: &mut state::State, ost: &mut overlay::State) -> Erm<()> {
         if let Some(s) = &mut self.visualizer.shader { s.delete(ctx); }
         self.visualizer.shader = None;
         Ok(())
     }
-    fn handle_binary(&mut self, ctx: &context::Context, st: &mut state::State, ost: &mut overlay::State, msg: &fig::BinaryMessage) -> Erm<()> {
-        if msg.event == b"overlay shader" {
-            let res: Erm<()> = (|| {
-                let mut reader = std::io::Cursor::new(&msg.data);
-                let author = fig::read_length_prefixed_utf8(&mut reader)?;
-                let shader = fig::read_length_prefixed_utf8(&mut reader)?;
-                self.visualizer.author = author.to_string();
-                if let Err(e) = self.visualizer.set(ctx, st, &shader) {
-                    log::warn!("error compiling shader: {}", e);
-                    self.visualizer.shader = None;
-                }
-                Ok(())
-            })();
-            if let Err(e) = res { log::warn!("malformed shader update: {}", e); }
+    fn handle_message(&mut self, ctx: &context::Context, st: &mut state::State, ost: &mut overlay::State, msg: &Message) -> Erm<()> {
+        if let Message::Shader { author, shader } = msg {
+            self.visualizer.author = author.to_string();
+            if let Err(e) = self.visualizer.set(ctx, st, shader) {
+                log::warn!("error compiling shader: {}", e);
+                self.visualizer.shader = None;
+            }
         }
         Ok(())
     }
@@ -43,13 +43,10 @@ impl overlay::Overlay for Overlay {
             s.bind(ctx);
             s.set_f32(
                 ctx, "opacity",
-                if let Some(t@toggle::Toggle { val: true, .. }) = ost.toggles.get(ctx, st, "shaderclarity") {
-                    ((st.tick - t.set_time) as f32 / 60.0).clamp(0.0, 1.0) * 0.5 + 0.5
-                } else if let Some(t@toggle::Toggle { val: false, .. }) = ost.toggles.get(ctx, st, "shaderclarity") {
-                    (1.0 - ((st.tick - t.set_time) as f32 / 60.0).clamp(0.0, 1.0)) * 0.5 + 0.5
-                } else {
-                    0.5
-                }
+                {
+                    let base = ost.params.f32(st, "shader_opacity", 0.5).clamp(0.0, 1.0);
+                    base + ost.toggles.level(ctx, st, "shaderclarity", 1.0, toggle::Easing::Linear) * (1.0 - base)
+                },
             );
             s.set_vec2(ctx, "resolution", &glam::Vec2::new(ctx.render_width, ctx.render_height));
             let elapsed = (st.tick - self.visualizer.tickset) as f32 / 60.0;
@@ -63,11 +60,11 @@ impl overlay::Overlay for Overlay {
             s.set_vec2(ctx, "mouse_cursor", &glam::Vec2::new(ost.info.mouse_cursor.0, ost.info.mouse_cursor.1));
             s.set_i32(ctx, "heartrate", ost.info.emacs_heartrate);
         }
-        if let Some(t@toggle::Toggle { val: true, .. }) = ost.toggles.get(ctx, st, "adblock") {
+        let adblock = ost.toggles.level(ctx, st, "adblock", 1.0, toggle::Easing::EaseInOut);
+        if adblock > 0.0 {
             st.bind_2d(ctx, &ost.assets.shader_flat);
             ost.assets.texture_adblock.bind(ctx);
-            let tr = 1.0 - ((st.tick - t.set_time) as f32 / 60.0).clamp(0.0, 1.0);
-            ost.assets.shader_flat.set_f32(ctx, "transparency", tr);
+            ost.assets.shader_flat.set_f32(ctx, "transparency", 1.0 - adblock);
             ost.assets.shader_flat.set_position_2d(
                 ctx, st,
                 &glam::Vec2::new(1100.0, 300.0),
@@ -86,4 +83,10 @@ impl overlay::Overlay for Overlay {
         ost.assets.font.render_text(ctx, st, &glam::Vec2::new(0.0, 0.0), &astr);
         Ok(())
     }
+    fn status(&self) -> Vec<(&'static str, lexpr::Value)> {
+        vec![
+            ("active", lexpr::Value::from(self.visualizer.shader.is_some())),
+            ("author", lexpr::Value::from(self.visualizer.author.as_str())),
+        ]
+    }
 }
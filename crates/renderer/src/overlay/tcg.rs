@@ -2,33 +2,21 @@ use teleia::*;
 
 use std::{cell::RefCell, io::Write, rc::Rc};
 
-use redis::Commands;
 use image::EncodableLayout;
 use glow::HasContext;
 use glam::Vec4Swizzles;
+use newton_messages::Message;
 
 use crate::overlay;
 
 pub const CARD_SLOTS: usize = 11;
 pub const CARD_SPACING: u64 = 300;
+pub const EVENTS: &[&[u8]] = &[b"overlay tcg generate"];
 pub const IWIDTH: usize = 160;
 pub const IHEIGHT: usize = 225;
 pub const WIDTH: f32 = IWIDTH as f32;
 pub const HEIGHT: f32 = IHEIGHT as f32;
 
-#[derive(Debug, Clone)]
-enum Error {
-    NotEnoughFields,
-}
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::NotEnoughFields => write!(f, "not enough fields"),
-        }
-    }
-}
-impl std::error::Error for Error {}
-
 #[derive(Debug, Clone)]
 struct Card {
     frames: u32, encoded: String,
@@ -50,6 +38,32 @@ struct Card {
     minted_date: String,
     flags: String,
 }
+impl Card {
+    fn new(c: &newton_messages::TcgCard) -> Self {
+        let color = |[r, g, b]: [u8; 3]| glam::Vec4::new(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0, 1.0);
+        Self {
+            frames: 20, encoded: c.encode(),
+            owner: c.owner.clone(),
+            owner_id: c.owner_id.clone(),
+            name: c.name.clone(),
+            ty: c.ty.clone(),
+            depicted_subject: c.depicted_subject.clone(),
+            element: c.element.clone(),
+            color: color(c.color),
+            faction: c.faction.clone(),
+            faction_color: color(c.faction_color),
+            equity: c.equity,
+            boost_level: c.boost_level.clone(),
+            rarity: c.rarity.clone(),
+            rarity_level: c.rarity_level,
+            body_text: c.body_text.clone(),
+            base_image_name: c.base_image_name.clone(),
+            set: c.set.clone(),
+            minted_date: c.minted_date.clone(),
+            flags: c.flags.clone(),
+        }
+    }
+}
 
 struct RenderedCardSlot {
     card: Option<Card>,
@@ -179,7 +193,10 @@ impl ImageEncoder {
     }
     fn finish(self) -> Option<Vec<u8>> {
         if self.is_finished() {
-            self.writer.finish().expect("failed to finish");
+            if let Err(e) = self.writer.finish() {
+                log::warn!("failed to finish card image: {}", e);
+                return None;
+            }
             Some(self.buf.replace(Vec::new()))
         } else { None }
     }
@@ -189,6 +206,7 @@ struct MarqueeSlot {
     card: RenderedCardSlot, 
     encoder: Option<ImageEncoder>,
     active: Option<u64>, // ticks active
+    distance: f32, // how far along the marquee, scaled by tcg_marquee_speed
     height_offset: bool,
 }
 impl MarqueeSlot {
@@ -227,6 +245,7 @@ struct Marquee {
     next_slot: usize,
     queue: std::collections::VecDeque<Card>,
     most_recent: u64,
+    last_render: Option<u64>,
     height_offset: bool,
 }
 impl Marquee {
@@ -237,12 +256,14 @@ impl Marquee {
             slots: std::array::from_fn(|_| MarqueeSlot {
                 card: RenderedCardSlot::new(ctx),
                 active: None,
+                distance: 0.0,
                 encoder: None,
                 height_offset: false,
             }),
             next_slot: 0,
             queue: std::collections::VecDeque::new(),
             most_recent: 0,
+            last_render: None,
             height_offset: false,
         }
     }
@@ -253,6 +274,7 @@ impl Marquee {
     ) {
         self.slots[sidx].card.set(ctx, st, ost, renderer, card.clone());
         self.slots[sidx].active = Some(st.tick);
+        self.slots[sidx].distance = 0.0;
         self.slots[sidx].height_offset = self.height_offset;
         self.slots[sidx].encoder = ImageEncoder::start(card.frames);
         self.height_offset = !self.height_offset;
@@ -289,10 +311,12 @@ impl Marquee {
             buf, "lcolonqtcg", &c.encoded,
         )?;
         let uuid = uuid::Uuid::new_v4();
-        let _: () = ost.redis_conn.hset("tcg:cards", uuid.to_string(), &with_meta)?;
+        let mut hset = redis::cmd("HSET");
+        hset.arg("tcg:cards").arg(uuid.to_string()).arg(&with_meta);
         let inventory_key = format!("tcg-inventory:{}", c.owner_id);
-        let _: () = ost.redis_conn.lpush(inventory_key, uuid.to_string())?;
-        Ok(())
+        let mut lpush = redis::cmd("LPUSH");
+        lpush.arg(inventory_key).arg(uuid.to_string());
+        ost.redis_write(&[hset, lpush])
     }
     pub fn render(&mut self, 
         ctx: &context::Context, st: &mut state::State, ost: &mut overlay::State,
@@ -301,7 +325,9 @@ impl Marquee {
         for s in self.slots.iter_mut() {
             if let Some(b) = s.encoder.take_if(|e| e.is_finished()).and_then(|enc| enc.finish()) {
                 if let Some(c) = &s.card.card {
-                    let _ = Self::upload_card(ctx, st, ost, &c, &b).expect("failed to upload");
+                    if let Err(e) = Self::upload_card(ctx, st, ost, &c, &b) {
+                        log::warn!("failed to upload card: {}", e);
+                    }
                 }
             }
         }
@@ -314,10 +340,14 @@ impl Marquee {
                 }
             }
         }
+        let speed = ost.params.f32(st, "tcg_marquee_speed", 1.0).max(0.0);
+        let elapsed = st.tick - self.last_render.unwrap_or(st.tick);
+        self.last_render = Some(st.tick);
         for s in self.slots.iter_mut() {
             if let Some(spawn) = s.active {
                 let p = st.tick - spawn;
-                let pos = (p as f32) / 200.0 - 7.0;
+                s.distance += elapsed as f32 * speed;
+                let pos = s.distance / 200.0 - 7.0;
                 if pos > 8.0 {
                     s.active = None;
                 } else {
@@ -551,66 +581,15 @@ impl Overlay {
 }
 
 impl overlay::Overlay for Overlay {
+    fn events(&self) -> &'static [&'static [u8]] {
+        EVENTS
+    }
     fn reset(&mut self, ctx: &context::Context, st: &mut state::State, ost: &mut overlay::State) -> Erm<()> {
         Ok(())
     }
-    fn handle_binary(&mut self, ctx: &context::Context, st: &mut state::State, ost: &mut overlay::State, msg: &fig::BinaryMessage) -> Erm<()> {
-        match &*msg.event {
-            b"overlay tcg generate" => {
-                let res: Erm<()> = (|| {
-                    let s = std::str::from_utf8(&msg.data)?.to_owned();
-                    log::info!("msg: {}", s);
-                    let mut sp = s.split("\t");
-                    let owner = sp.next().ok_or(Error::NotEnoughFields)?.to_owned();
-                    let owner_id = sp.next().ok_or(Error::NotEnoughFields)?.to_owned();
-                    let name = sp.next().ok_or(Error::NotEnoughFields)?.to_owned();
-                    let ty = sp.next().ok_or(Error::NotEnoughFields)?.to_owned();
-                    let depicted_subject = sp.next().ok_or(Error::NotEnoughFields)?.to_owned();
-                    let element = sp.next().ok_or(Error::NotEnoughFields)?.to_owned();
-                    let color = sp.next().ok_or(Error::NotEnoughFields)?.to_owned();
-                    let r = i64::from_str_radix(&color[1..=2], 16)?;
-                    let g = i64::from_str_radix(&color[3..=4], 16)?;
-                    let b = i64::from_str_radix(&color[5..=6], 16)?;
-                    let faction = sp.next().ok_or(Error::NotEnoughFields)?.to_owned();
-                    let faction_color = sp.next().ok_or(Error::NotEnoughFields)?.to_owned();
-                    let f_r = i64::from_str_radix(&faction_color[1..=2], 16)?;
-                    let f_g = i64::from_str_radix(&faction_color[3..=4], 16)?;
-                    let f_b = i64::from_str_radix(&faction_color[5..=6], 16)?;
-                    let equity = sp.next().ok_or(Error::NotEnoughFields)?.parse()?;
-                    let boost_level = sp.next().ok_or(Error::NotEnoughFields)?.to_owned();
-                    let rarity = sp.next().ok_or(Error::NotEnoughFields)?.to_owned();
-                    let rarity_level = sp.next().ok_or(Error::NotEnoughFields)?.parse()?;
-                    let body_text = sp.next().ok_or(Error::NotEnoughFields)?.to_owned();
-                    let base_image_name = sp.next().ok_or(Error::NotEnoughFields)?.to_owned();
-                    let set = sp.next().ok_or(Error::NotEnoughFields)?.to_owned();
-                    let minted_date = sp.next().ok_or(Error::NotEnoughFields)?.to_owned();
-                    let flags = sp.next().ok_or(Error::NotEnoughFields)?.to_owned();
-                    let card = Card {
-                        frames: 20, encoded: s.clone(),
-                        owner,
-                        owner_id,
-                        name,
-                        ty,
-                        depicted_subject,
-                        element,
-                        color: glam::Vec4::new(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0, 1.0),
-                        faction,
-                        faction_color: glam::Vec4::new(f_r as f32 / 255.0, f_g as f32 / 255.0, f_b as f32 / 255.0, 1.0),
-                        equity,
-                        boost_level,
-                        rarity, rarity_level,
-                        body_text,
-                        base_image_name,
-                        set,
-                        minted_date,
-                        flags,
-                    };
-                    self.marquee.add(ctx, st, ost, &self.renderer, card);
-                    Ok(())
-                })();
-                if let Err(e) = res { log::warn!("malformed TCG generate: {}", e); }
-            },
-            _ => {},
+    fn handle_message(&mut self, ctx: &context::Context, st: &mut state::State, ost: &mut overlay::State, msg: &Message) -> Erm<()> {
+        if let Message::TcgGenerate(c) = msg {
+            self.marquee.add(ctx, st, ost, &self.renderer, Card::new(c));
         }
         Ok(())
     }
@@ -620,4 +599,11 @@ impl overlay::Overlay for Overlay {
         self.marquee.render(ctx, st, ost, &self.renderer);
         Ok(())
     }
+    fn status(&self) -> Vec<(&'static str, lexpr::Value)> {
+        vec![
+            ("queue", lexpr::Value::from(self.marquee.queue.len() as u64)),
+            ("slots", lexpr::Value::from(self.marquee.slots.iter().filter(|s| s.active.is_some()).count() as u64)),
+            ("capacity", lexpr::Value::from(CARD_SLOTS as u64)),
+        ]
+    }
 }
@@ -0,0 +1,105 @@
+use teleia::*;
+use std::collections::HashMap;
+
+use newton_messages::ParamValue;
+
+/// A parameter moving from one value to another over some number of ticks.
+/// Strings (and changes between types) switch over immediately.
+#[derive(Debug, Clone)]
+pub struct Param {
+    pub from: ParamValue,
+    pub to: ParamValue,
+    pub set_time: u64,
+    pub duration: u64, // ticks
+}
+impl Param {
+    pub fn value(&self, st: &state::State) -> ParamValue {
+        if self.duration == 0 { return self.to.clone() }
+        let t = (st.tick.saturating_sub(self.set_time) as f32 / self.duration as f32).clamp(0.0, 1.0);
+        let lerp = |a: f32, b: f32| a + (b - a) * t;
+        match (&self.from, &self.to) {
+            (ParamValue::Float(a), ParamValue::Float(b)) => ParamValue::Float(lerp(*a, *b)),
+            (ParamValue::Int(a), ParamValue::Int(b)) => ParamValue::Int(lerp(*a as f32, *b as f32).round() as i64),
+            (ParamValue::Color(a), ParamValue::Color(b)) =>
+                ParamValue::Color(std::array::from_fn(|i| lerp(a[i] as f32, b[i] as f32).round() as u8)),
+            _ => self.to.clone(),
+        }
+    }
+}
+
+/// Typed values tunable over the bus, e.g. "overlay param set shader_opacity 0.8 2s".
+/// Overlays read them with a default, so unset parameters behave as before.
+pub struct Params {
+    params: HashMap<String, Param>,
+}
+impl Params {
+    pub fn new() -> Self {
+        Self {
+            params: HashMap::new(),
+        }
+    }
+    /// Move a parameter to a new value, interpolating over duration seconds if given.
+    pub fn set(&mut self, st: &state::State, nm: &str, val: ParamValue, duration: Option<f32>) {
+        let from = self.get(st, nm).unwrap_or_else(|| val.clone());
+        self.params.insert(nm.to_string(), Param {
+            from,
+            to: val,
+            set_time: st.tick,
+            duration: duration.map(|d| (d * 60.0) as u64).unwrap_or(0),
+        });
+    }
+    /// Target values, ignoring any interpolation in progress.
+    pub fn persisted(&self) -> Vec<(String, ParamValue)> {
+        let mut ret: Vec<(String, ParamValue)> = self.params.iter()
+            .map(|(nm, p)| (nm.clone(), p.to.clone()))
+            .collect();
+        ret.sort_by(|a, b| a.0.cmp(&b.0));
+        ret
+    }
+    pub fn restore(&mut self, nm: &str, val: ParamValue) {
+        self.params.insert(nm.to_string(), Param { from: val.clone(), to: val, set_time: 0, duration: 0 });
+    }
+    pub fn unset(&mut self, nm: &str) {
+        self.params.remove(nm);
+    }
+    pub fn reset(&mut self) {
+        self.params.clear();
+    }
+    pub fn get(&self, st: &state::State, nm: &str) -> Option<ParamValue> {
+        self.params.get(nm).map(|p| p.value(st))
+    }
+    pub fn f32(&self, st: &state::State, nm: &str, default: f32) -> f32 {
+        match self.get(st, nm) {
+            Some(ParamValue::Float(x)) => x,
+            Some(ParamValue::Int(i)) => i as f32,
+            _ => default,
+        }
+    }
+    pub fn i64(&self, st: &state::State, nm: &str, default: i64) -> i64 {
+        match self.get(st, nm) {
+            Some(ParamValue::Int(i)) => i,
+            Some(ParamValue::Float(x)) => x.round() as i64,
+            _ => default,
+        }
+    }
+    pub fn color(&self, st: &state::State, nm: &str, default: glam::Vec4) -> glam::Vec4 {
+        match self.get(st, nm) {
+            Some(ParamValue::Color(c)) => glam::Vec4::from_array(c.map(|x| x as f32 / 255.0)),
+            _ => default,
+        }
+    }
+    pub fn string(&self, st: &state::State, nm: &str, default: &str) -> String {
+        match self.get(st, nm) {
+            Some(ParamValue::String(s)) => s,
+            _ => default.to_string(),
+        }
+    }
+    /// An alist of current values, e.g. (("shader_opacity" . "0.8") ("tint" . "#ff0000ff")).
+    pub fn status(&self, st: &state::State) -> lexpr::Value {
+        let mut names: Vec<&String> = self.params.keys().collect();
+        names.sort();
+        lexpr::Value::list(names.into_iter().map(|nm| {
+            lexpr::Value::cons(nm.as_str(), self.params[nm].value(st).to_string())
+        }))
+    }
+}
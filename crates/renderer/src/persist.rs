@@ -0,0 +1,161 @@
+use teleia::*;
+
+use newton_messages::ParamValue;
+
+pub const TOGGLES_KEY: &str = "overlay:toggles";
+pub const PARAMS_KEY: &str = "overlay:params";
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    Malformed(String),
+}
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed(s) => write!(f, "malformed saved state: {}", s),
+        }
+    }
+}
+impl std::error::Error for Error {}
+
+/// The toggles and parameters that survive a restart.
+/// In Redis these are two hashes; on disk they are an s-expression like
+/// ((toggles ("adblock" . #t)) (params ("shader_opacity" . "0.8")))
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    pub toggles: Vec<(String, bool)>,
+    pub params: Vec<(String, ParamValue)>,
+}
+impl Snapshot {
+    pub fn to_sexp(&self) -> lexpr::Value {
+        lexpr::Value::list(vec![
+            lexpr::Value::cons(lexpr::Value::symbol("toggles"), lexpr::Value::list(
+                self.toggles.iter().map(|(nm, v)| lexpr::Value::cons(nm.as_str(), *v))
+            )),
+            lexpr::Value::cons(lexpr::Value::symbol("params"), lexpr::Value::list(
+                self.params.iter().map(|(nm, v)| lexpr::Value::cons(nm.as_str(), v.to_string()))
+            )),
+        ])
+    }
+    pub fn from_sexp(v: &lexpr::Value) -> Erm<Self> {
+        let malformed = |v: &lexpr::Value| Error::Malformed(v.to_string());
+        let mut ret = Self::default();
+        for ent in v.list_iter().ok_or_else(|| malformed(v))? {
+            let c = ent.as_cons().ok_or_else(|| malformed(ent))?;
+            for pair in c.cdr().list_iter().ok_or_else(|| malformed(ent))? {
+                let p = pair.as_cons().ok_or_else(|| malformed(pair))?;
+                let nm = p.car().as_str().ok_or_else(|| malformed(pair))?.to_owned();
+                match c.car().as_symbol() {
+                    Some("toggles") => ret.toggles.push((nm, p.cdr().as_bool().ok_or_else(|| malformed(pair))?)),
+                    Some("params") => ret.params.push((nm, ParamValue::parse(p.cdr().as_str().ok_or_else(|| malformed(pair))?)?)),
+                    _ => return Err(malformed(ent).into()),
+                }
+            }
+        }
+        Ok(ret)
+    }
+    /// This snapshot with every entry of over added, replacing any of the same name.
+    pub fn merged(mut self, over: Self) -> Self {
+        for (nm, v) in over.toggles {
+            self.toggles.retain(|(n, _)| *n != nm);
+            self.toggles.push((nm, v));
+        }
+        for (nm, v) in over.params {
+            self.params.retain(|(n, _)| *n != nm);
+            self.params.push((nm, v));
+        }
+        self.toggles.sort();
+        self.params.sort_by(|a, b| a.0.cmp(&b.0));
+        self
+    }
+    /// Returns None if nothing has been saved.
+    pub fn load_file(path: &std::path::Path) -> Erm<Option<Self>> {
+        let src = match std::fs::read_to_string(path) {
+            Ok(src) => src,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(Self::from_sexp(&lexpr::from_str(&src)?)?))
+    }
+    pub fn save_file(&self, path: &std::path::Path) -> Erm<()> {
+        // write then rename, so a crash never leaves a half-written file
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, self.to_sexp().to_string())?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+    pub fn load_redis(conn: &mut redis::Connection) -> Erm<Self> {
+        let toggles: Vec<(String, String)> = redis::cmd("HGETALL").arg(TOGGLES_KEY).query(conn)?;
+        let params: Vec<(String, String)> = redis::cmd("HGETALL").arg(PARAMS_KEY).query(conn)?;
+        Ok(Self {
+            toggles: toggles.into_iter().map(|(nm, v)| (nm, v == "1")).collect(),
+            params: params.into_iter()
+                .map(|(nm, v)| Ok((nm, ParamValue::parse(&v)?)))
+                .collect::<Result<_, newton_messages::Error>>()?,
+        })
+    }
+    /// Commands replacing whatever is stored in Redis with this snapshot.
+    pub fn redis_cmds(&self) -> Vec<redis::Cmd> {
+        let mut ret = vec![
+            redis::cmd("DEL").arg(TOGGLES_KEY).clone(),
+            redis::cmd("DEL").arg(PARAMS_KEY).clone(),
+        ];
+        if !self.toggles.is_empty() {
+            let mut hset = redis::cmd("HSET");
+            hset.arg(TOGGLES_KEY);
+            for (nm, v) in &self.toggles { hset.arg(nm).arg(if *v { "1" } else { "0" }); }
+            ret.push(hset);
+        }
+        if !self.params.is_empty() {
+            let mut hset = redis::cmd("HSET");
+            hset.arg(PARAMS_KEY);
+            for (nm, v) in &self.params { hset.arg(nm).arg(v.to_string()); }
+            ret.push(hset);
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn param(s: &str) -> ParamValue {
+        ParamValue::parse(s).unwrap()
+    }
+
+    #[test]
+    fn change_before_connect() {
+        // saved in Redis by an earlier session
+        let redis = Snapshot {
+            toggles: vec![("adblock".to_owned(), true), ("hat".to_owned(), false)],
+            params: vec![("shader_opacity".to_owned(), param("0.8"))],
+        };
+        // written to the state file when a toggle changed before Redis connected
+        let file = Snapshot {
+            toggles: vec![("hat".to_owned(), true)],
+            params: vec![("drawing_fade".to_owned(), param("3"))],
+        };
+        let merged = redis.merged(file);
+        assert_eq!(merged.toggles, vec![("adblock".to_owned(), true), ("hat".to_owned(), true)]);
+        assert_eq!(merged.params, vec![
+            ("drawing_fade".to_owned(), param("3")),
+            ("shader_opacity".to_owned(), param("0.8")),
+        ]);
+        let cmds: Vec<Vec<u8>> = merged.redis_cmds().iter().map(|c| c.get_packed_command()).collect();
+        let args = |c: &[u8]| String::from_utf8_lossy(c).into_owned();
+        assert!(cmds.iter().any(|c| args(c).contains("HSET") && args(c).contains("adblock")));
+        assert!(cmds.iter().any(|c| args(c).contains("HSET") && args(c).contains("shader_opacity")));
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let snap = Snapshot {
+            toggles: vec![("adblock".to_owned(), true)],
+            params: vec![("shader_opacity".to_owned(), param("0.8")), ("title".to_owned(), param("\"a b\""))],
+        };
+        let back = Snapshot::from_sexp(&lexpr::from_str(&snap.to_sexp().to_string()).unwrap()).unwrap();
+        assert_eq!(back.toggles, snap.toggles);
+        assert_eq!(back.params, snap.params);
+    }
+}
@@ -0,0 +1,118 @@
+use teleia::*;
+
+use std::io::Write;
+use byteorder::{LE, ReadBytesExt, WriteBytesExt};
+use newton_server::protocol;
+
+use crate::config;
+
+/// A single captured bus message.
+/// On disk, each entry is a little-endian u64 timestamp in microseconds
+/// since the recording started, then the event and data, each prefixed
+/// with a little-endian u32 length.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub time: u64,
+    pub event: Vec<u8>,
+    pub data: Vec<u8>,
+}
+impl Entry {
+    pub fn write<W: Write>(&self, w: &mut W) -> Erm<()> {
+        w.write_u64::<LE>(self.time)?;
+        w.write_u32::<LE>(self.event.len() as u32)?;
+        w.write_all(&self.event)?;
+        w.write_u32::<LE>(self.data.len() as u32)?;
+        w.write_all(&self.data)?;
+        Ok(())
+    }
+    fn read_bytes(reader: &mut std::io::Cursor<&[u8]>) -> Erm<Vec<u8>> {
+        let len = reader.read_u32::<LE>()? as usize;
+        let start = reader.position() as usize;
+        let ret = reader.get_ref().get(start..start + len).ok_or("truncated recording")?.to_vec();
+        reader.set_position((start + len) as u64);
+        Ok(ret)
+    }
+    pub fn read(reader: &mut std::io::Cursor<&[u8]>) -> Erm<Self> {
+        let time = reader.read_u64::<LE>()?;
+        let event = Self::read_bytes(reader)?;
+        let data = Self::read_bytes(reader)?;
+        Ok(Self { time, event, data })
+    }
+}
+
+pub fn load(path: &str) -> Erm<Vec<Entry>> {
+    let buf = std::fs::read(path)?;
+    let mut reader = std::io::Cursor::new(&buf[..]);
+    let mut ret = Vec::new();
+    while (reader.position() as usize) < buf.len() {
+        match Entry::read(&mut reader) {
+            Ok(e) => ret.push(e),
+            // recording stops on Ctrl-C, which can land in the middle of a write
+            Err(e) => {
+                log::warn!("ignoring truncated final entry in {} after {} messages: {}", path, ret.len(), e);
+                break;
+            },
+        }
+    }
+    Ok(ret)
+}
+
+/// Connect to the bus and append every message we receive to a file until interrupted.
+/// This blocks on the socket rather than polling a fig::BinaryClient, so each message
+/// is timestamped as soon as it arrives.
+pub fn record(cfg: &config::Config, path: &str, subscriptions: &[&[u8]]) -> Erm<()> {
+    let mut bus = std::net::TcpStream::connect(&cfg.bus)
+        .map_err(|e| config::Error::ConnectFailed("bus", cfg.bus.clone(), e.to_string()))?;
+    for ev in subscriptions { protocol::Command::Subscribe(ev.to_vec()).write(&mut bus)?; }
+    let mut bus = std::io::BufReader::new(bus);
+    let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let start = std::time::Instant::now();
+    log::info!("recording bus traffic to {}", path);
+    let mut count = 0;
+    loop {
+        let msg = protocol::Message::read(&mut bus)?;
+        let ent = Entry {
+            time: start.elapsed().as_micros() as u64,
+            event: msg.event,
+            data: msg.data,
+        };
+        ent.write(&mut out)?;
+        out.flush()?;
+        count += 1;
+        log::info!("{} {:.3}s {}", count, ent.time as f64 / 1_000_000.0, String::from_utf8_lossy(&ent.event));
+    }
+}
+
+/// Feeds a recording back in as if it were arriving from the bus.
+/// Time is measured in ticks so that playback is deterministic.
+pub struct Player {
+    entries: Vec<Entry>,
+    next: usize,
+    speed: f64,
+    start_tick: Option<u64>,
+}
+impl Player {
+    pub fn new(entries: Vec<Entry>, speed: f64) -> Self {
+        Self {
+            entries,
+            next: 0,
+            speed,
+            start_tick: None,
+        }
+    }
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.entries.len()
+    }
+    pub fn pump(&mut self, tick: u64) -> Option<fig::BinaryMessage> {
+        let start = *self.start_tick.get_or_insert(tick);
+        let elapsed = ((tick - start) as f64 / 60.0 * 1_000_000.0 * self.speed) as u64;
+        let ent = self.entries.get(self.next)?;
+        if ent.time > elapsed { return None }
+        self.next += 1;
+        if self.is_finished() { log::info!("replay finished"); }
+        Some(fig::BinaryMessage {
+            event: ent.event.clone(),
+            data: ent.data.clone(),
+        })
+    }
+}
@@ -0,0 +1,98 @@
+use teleia::*;
+
+use std::{io::Write, path::PathBuf};
+use byteorder::{LE, ReadBytesExt, WriteBytesExt};
+
+/// Whether a Redis error means the connection is gone, as opposed to the
+/// server rejecting a command (e.g. WRONGTYPE), which retrying will not fix.
+pub fn is_connection_error(e: &redis::RedisError) -> bool {
+    e.is_io_error() || e.is_connection_dropped() || e.is_connection_refusal() || e.is_timeout()
+}
+
+/// Redis writes that could not be delivered, saved to disk until the
+/// connection comes back. Each file holds one batch of commands that is
+/// replayed atomically.
+pub struct Spool {
+    dir: PathBuf,
+    pending: usize,
+}
+impl Spool {
+    pub fn new(dir: &str) -> Self {
+        let mut ret = Self {
+            dir: PathBuf::from(dir),
+            pending: 0,
+        };
+        ret.pending = ret.entries().len();
+        if ret.pending > 0 { log::info!("{} spooled Redis writes pending", ret.pending); }
+        ret
+    }
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+    fn entries(&self) -> Vec<PathBuf> {
+        let mut ret: Vec<PathBuf> = std::fs::read_dir(&self.dir).into_iter().flatten()
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|x| x == "spool"))
+            .collect();
+        ret.sort();
+        ret
+    }
+    pub fn push(&mut self, cmds: &[redis::Cmd]) -> Erm<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+        let path = self.dir.join(format!("{:020}-{}.spool", now.as_micros(), uuid::Uuid::new_v4()));
+        let mut buf = Vec::new();
+        buf.write_u32::<LE>(cmds.len() as u32)?;
+        for c in cmds {
+            let args: Vec<&[u8]> = c.args_iter().filter_map(|a| match a {
+                redis::Arg::Simple(x) => Some(x),
+                redis::Arg::Cursor => None,
+            }).collect();
+            buf.write_u32::<LE>(args.len() as u32)?;
+            for a in args {
+                buf.write_u32::<LE>(a.len() as u32)?;
+                buf.write_all(a)?;
+            }
+        }
+        std::fs::write(&path, buf)?;
+        self.pending += 1;
+        Ok(())
+    }
+    fn load(path: &PathBuf) -> Erm<redis::Pipeline> {
+        let data = std::fs::read(path)?;
+        let mut reader = std::io::Cursor::new(&data);
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for _ in 0..reader.read_u32::<LE>()? {
+            let mut cmd = redis::Cmd::new();
+            for _ in 0..reader.read_u32::<LE>()? {
+                let len = reader.read_u32::<LE>()? as usize;
+                let start = reader.position() as usize;
+                let arg = data.get(start..start + len).ok_or("truncated spool entry")?;
+                cmd.arg(arg);
+                reader.set_position((start + len) as u64);
+            }
+            pipe.add_command(cmd);
+        }
+        Ok(pipe)
+    }
+    /// Replay spooled writes oldest first, stopping at the first connection error.
+    /// Entries the server rejects are discarded, since replaying them would fail forever.
+    pub fn flush(&mut self, conn: &mut redis::Connection) -> Result<(), redis::RedisError> {
+        for path in self.entries() {
+            match Self::load(&path) {
+                Ok(pipe) => match pipe.query::<()>(conn) {
+                    Ok(()) => {},
+                    Err(e) if is_connection_error(&e) => return Err(e),
+                    Err(e) => log::warn!("discarding spool entry {} rejected by Redis: {}", path.display(), e),
+                },
+                Err(e) => log::warn!("discarding malformed spool entry {}: {}", path.display(), e),
+            }
+            if let Err(e) = std::fs::remove_file(&path) {
+                log::warn!("failed to remove spool entry {}: {}", path.display(), e);
+            }
+        }
+        self.pending = 0;
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use teleia::*;
+
+use std::io::Write;
+use std::os::unix::fs::FileTypeExt;
+
+/// Build an association list with symbol keys, e.g. ((tick . 60) (layers "shader" "tcg")).
+pub fn alist<'a>(entries: impl IntoIterator<Item = (&'a str, lexpr::Value)>) -> lexpr::Value {
+    lexpr::Value::list(entries.into_iter().map(|(k, v)| lexpr::Value::cons(lexpr::Value::symbol(k), v)))
+}
+
+/// Frame timing measured around Game::update and Game::render.
+pub struct Timing {
+    pub update: std::time::Duration,
+    pub render: std::time::Duration,
+    pub frame: std::time::Duration, // time between the starts of consecutive renders
+    last_render: Option<std::time::Instant>,
+}
+impl Timing {
+    pub fn new() -> Self {
+        Self {
+            update: std::time::Duration::ZERO,
+            render: std::time::Duration::ZERO,
+            frame: std::time::Duration::ZERO,
+            last_render: None,
+        }
+    }
+    pub fn start_render(&mut self) -> std::time::Instant {
+        let now = std::time::Instant::now();
+        if let Some(prev) = self.last_render { self.frame = now - prev; }
+        self.last_render = Some(now);
+        now
+    }
+    pub fn status(&self) -> lexpr::Value {
+        let ms = |d: std::time::Duration| lexpr::Value::from(d.as_secs_f64() * 1000.0);
+        alist([
+            ("update-ms", ms(self.update)),
+            ("render-ms", ms(self.render)),
+            ("frame-ms", ms(self.frame)),
+        ])
+    }
+}
+
+/// Serves a status report to anything that connects to a Unix socket,
+/// e.g. `socat - UNIX-CONNECT:/run/user/1000/newton.sock`.
+pub struct Endpoint {
+    path: std::path::PathBuf,
+    listener: std::os::unix::net::UnixListener,
+}
+impl Endpoint {
+    pub fn bind(path: &str) -> Erm<Self> {
+        // clean up a socket left behind by a previous run, but never anything else
+        if std::fs::metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
+        }
+        let listener = std::os::unix::net::UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        log::info!("serving status on {}", path);
+        Ok(Self {
+            path: std::path::PathBuf::from(path),
+            listener,
+        })
+    }
+    /// Every client currently waiting for a report.
+    pub fn accept(&self) -> Vec<std::os::unix::net::UnixStream> {
+        let mut ret = Vec::new();
+        while let Ok((s, _)) = self.listener.accept() {
+            // reports are written from the render thread, which must never wait on a client
+            match s.set_nonblocking(true) {
+                Ok(()) => ret.push(s),
+                Err(e) => log::warn!("failed to set up status client: {}", e),
+            }
+        }
+        ret
+    }
+    /// Send each client the report and hang up. Clients that are not reading
+    /// once the socket buffer fills are dropped with whatever they got.
+    pub fn serve(clients: Vec<std::os::unix::net::UnixStream>, report: &str) {
+        let line = format!("{}\n", report);
+        for mut c in clients {
+            match c.write_all(line.as_bytes()) {
+                Ok(()) => {},
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => log::warn!("dropping status client that is not reading"),
+                Err(e) => log::warn!("failed to send status: {}", e),
+            }
+        }
+    }
+}
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
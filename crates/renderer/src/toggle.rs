@@ -1,10 +1,53 @@
 use teleia::*;
 use std::collections::HashMap;
 
+/// Shapes for toggle transitions, mapping linear progress in [0, 1] to eased progress.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Easing {
+    Linear,
+    EaseInOut,
+    Bounce,
+}
+impl Easing {
+    pub fn apply(&self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Self::Linear => t,
+            Self::EaseInOut => t * t * (3.0 - 2.0 * t),
+            Self::Bounce => {
+                // the usual "ease out bounce": four parabolic arcs of decreasing height
+                let (n, d) = (7.5625, 2.75);
+                if t < 1.0 / d {
+                    n * t * t
+                } else if t < 2.0 / d {
+                    let t = t - 1.5 / d; n * t * t + 0.75
+                } else if t < 2.5 / d {
+                    let t = t - 2.25 / d; n * t * t + 0.9375
+                } else {
+                    let t = t - 2.625 / d; n * t * t + 0.984375
+                }
+            },
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Toggle {
     pub val: bool,
     pub set_time: u64,
+    pub expires: Option<u64>, // tick at which val flips back
+}
+impl Toggle {
+    /// How far along the transition to the current value is, in [0, 1].
+    pub fn progress(&self, st: &state::State, secs: f32, easing: Easing) -> f32 {
+        let elapsed = st.tick.saturating_sub(self.set_time) as f32 / 60.0;
+        easing.apply(if secs > 0.0 { elapsed / secs } else { 1.0 })
+    }
+    /// Like progress, but running from 0 when off to 1 when on.
+    pub fn level(&self, st: &state::State, secs: f32, easing: Easing) -> f32 {
+        let p = self.progress(st, secs, easing);
+        if self.val { p } else { 1.0 - p }
+    }
 }
 
 pub struct Toggles {
@@ -16,34 +59,65 @@ impl Toggles {
             toggles: HashMap::new(),
         }
     }
-    pub fn set(&mut self, ctx: &context::Context, st: &state::State, nm: &str, val: bool) {
-        self.toggles.insert(nm.to_string(), Toggle { val, set_time: st.tick });
+    /// Set a toggle, reverting it after duration seconds if given.
+    pub fn set(&mut self, ctx: &context::Context, st: &state::State, nm: &str, val: bool, duration: Option<f32>) {
+        self.toggles.insert(nm.to_string(), Toggle {
+            val,
+            set_time: st.tick,
+            expires: duration.map(|d| st.tick + (d * 60.0) as u64),
+        });
     }
     pub fn get(&self, ctx: &context::Context, st: &state::State, nm: &str) -> Option<Toggle> {
         self.toggles.get(nm).cloned()
     }
+    /// The eased level of a toggle, from 0 when off to 1 when on.
+    /// Toggles that have never been set are off.
+    pub fn level(&self, ctx: &context::Context, st: &state::State, nm: &str, secs: f32, easing: Easing) -> f32 {
+        self.toggles.get(nm).map(|t| t.level(st, secs, easing)).unwrap_or(0.0)
+    }
+    /// The values worth keeping across a restart: timed toggles are saved
+    /// as the value they will revert to.
+    pub fn persisted(&self) -> Vec<(String, bool)> {
+        let mut ret: Vec<(String, bool)> = self.toggles.iter()
+            .map(|(nm, t)| (nm.clone(), t.val != t.expires.is_some()))
+            .collect();
+        ret.sort();
+        ret
+    }
+    /// Set a toggle as if it had been set long ago, so no transition plays.
+    pub fn restore(&mut self, nm: &str, val: bool) {
+        self.toggles.insert(nm.to_string(), Toggle { val, set_time: 0, expires: None });
+    }
     pub fn reset(&mut self) {
         self.toggles.clear();
     }
-    pub fn handle(&mut self,
-        ctx: &context::Context, st: &state::State,
-        msg: fig::BinaryMessage
-    ) {
-        let nm = if let Ok(s) = str::from_utf8(&msg.data) { s } else {
-            log::warn!("failed to decode toggle name");
-            return;
-        };
+    pub fn toggle(&mut self, ctx: &context::Context, st: &state::State, nm: &str, duration: Option<f32>) {
         let prev = self.get(ctx, st, nm).map(|t| t.val).unwrap_or(false);
-        self.set(ctx, st, nm, !prev);
-    }
-    pub fn handle_set(&mut self,
-        ctx: &context::Context, st: &state::State,
-        msg: fig::BinaryMessage, val: bool
-    ) {
-        let nm = if let Ok(s) = str::from_utf8(&msg.data) { s } else {
-            log::warn!("failed to decode toggle name");
-            return;
-        };
-        self.set(ctx, st, nm, val);
+        self.set(ctx, st, nm, !prev, duration);
+    }
+    /// Flip back any toggles whose duration has run out.
+    pub fn update(&mut self, ctx: &context::Context, st: &state::State) {
+        for (nm, t) in self.toggles.iter_mut() {
+            if let Some(exp) = t.expires.filter(|exp| st.tick >= *exp) {
+                log::info!("toggle {} expired", nm);
+                t.val = !t.val;
+                t.set_time = exp;
+                t.expires = None;
+            }
+        }
+    }
+    /// An alist describing every toggle, e.g. (("adblock" (on . #t) (remaining . 12.5))).
+    pub fn status(&self, st: &state::State) -> lexpr::Value {
+        let mut names: Vec<&String> = self.toggles.keys().collect();
+        names.sort();
+        lexpr::Value::list(names.into_iter().map(|nm| {
+            let t = &self.toggles[nm];
+            let mut fields = vec![lexpr::Value::cons(lexpr::Value::symbol("on"), t.val)];
+            if let Some(exp) = t.expires {
+                let remaining = exp.saturating_sub(st.tick) as f64 / 60.0;
+                fields.push(lexpr::Value::cons(lexpr::Value::symbol("remaining"), remaining));
+            }
+            lexpr::Value::cons(nm.as_str(), lexpr::Value::list(fields))
+        }))
     }
 }
@@ -0,0 +1,149 @@
+use std::{collections::HashSet, net::{Shutdown, TcpListener, TcpStream}, sync::{Arc, Mutex, mpsc}, time::Duration};
+
+use crate::{Erm, protocol};
+
+/// How long a write to a client may block before it counts as stalled and is dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Client {
+    id: u64,
+    subscriptions: HashSet<Vec<u8>>,
+    stream: TcpStream, // kept to shut the connection down when dropping the client
+    queue: mpsc::Sender<Arc<protocol::Message>>, // drained by the client's writer thread
+}
+
+/// A minimal pub/sub broker: every published message is forwarded to
+/// each client subscribed to its event. Each client has its own writer thread,
+/// so one that stops reading never holds up publishers or other clients.
+#[derive(Clone)]
+pub struct Broker {
+    clients: Arc<Mutex<Vec<Client>>>,
+}
+impl Default for Broker {
+    fn default() -> Self { Self::new() }
+}
+impl Broker {
+    pub fn new() -> Self {
+        Self {
+            clients: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+    pub fn serve(&self, addr: &str) -> Erm<()> {
+        let listener = TcpListener::bind(addr)?;
+        log::info!("listening on {}", addr);
+        self.serve_listener(listener)
+    }
+    pub fn serve_listener(&self, listener: TcpListener) -> Erm<()> {
+        let mut next_id = 0;
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(s) => s,
+                Err(e) => { log::warn!("failed to accept connection: {}", e); continue; },
+            };
+            let id = next_id;
+            next_id += 1;
+            let broker = self.clone();
+            std::thread::spawn(move || {
+                let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+                log::info!("client {} connected from {}", id, peer);
+                if let Err(e) = broker.handle(id, stream) {
+                    log::info!("client {} disconnected: {}", id, e);
+                }
+                broker.remove(id);
+            });
+        }
+        Ok(())
+    }
+    fn handle(&self, id: u64, stream: TcpStream) -> Erm<()> {
+        let (queue, rx) = mpsc::channel::<Arc<protocol::Message>>();
+        let mut writer = stream.try_clone()?;
+        writer.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        std::thread::spawn(move || {
+            // ends when the client is removed and its queue dropped
+            for msg in rx {
+                if let Err(e) = msg.write(&mut writer) {
+                    log::info!("failed to write to client {}: {}", id, e);
+                    // also ends the client's reader thread, which removes it
+                    let _ = writer.shutdown(Shutdown::Both);
+                    break;
+                }
+            }
+        });
+        self.clients.lock().expect("client list poisoned").push(Client {
+            id,
+            subscriptions: HashSet::new(),
+            stream: stream.try_clone()?,
+            queue,
+        });
+        let mut reader = std::io::BufReader::new(stream);
+        loop {
+            match protocol::Command::read(&mut reader)? {
+                protocol::Command::Subscribe(ev) => self.subscribe(id, ev),
+                protocol::Command::Publish(event, data) => self.publish(&protocol::Message { event, data }),
+            }
+        }
+    }
+    fn remove(&self, id: u64) {
+        self.clients.lock().expect("client list poisoned").retain(|c| c.id != id);
+    }
+    fn subscribe(&self, id: u64, ev: Vec<u8>) {
+        log::info!("client {} subscribed to {}", id, String::from_utf8_lossy(&ev));
+        let mut clients = self.clients.lock().expect("client list poisoned");
+        if let Some(c) = clients.iter_mut().find(|c| c.id == id) {
+            c.subscriptions.insert(ev);
+        }
+    }
+    pub fn publish(&self, msg: &protocol::Message) {
+        log::info!("publish {} ({} bytes)", String::from_utf8_lossy(&msg.event), msg.data.len());
+        let msg = Arc::new(msg.clone());
+        let mut clients = self.clients.lock().expect("client list poisoned");
+        clients.retain(|c| {
+            if !c.subscriptions.contains(&msg.event) { return true }
+            match c.queue.send(msg.clone()) {
+                Ok(()) => true,
+                Err(_) => {
+                    // the writer thread gave up on a stalled or closed connection
+                    log::info!("dropping client {}: not reading", c.id);
+                    let _ = c.stream.shutdown(Shutdown::Both);
+                    false
+                },
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect(addr: std::net::SocketAddr, events: &[&[u8]]) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        for ev in events { protocol::Command::Subscribe(ev.to_vec()).write(&mut stream).unwrap(); }
+        stream
+    }
+
+    #[test]
+    fn forwards_to_subscribers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let broker = Broker::new();
+        let server = broker.clone();
+        std::thread::spawn(move || { let _ = server.serve_listener(listener); });
+        let mut a = connect(addr, &[b"overlay toggle"]);
+        let mut b = connect(addr, &[b"overlay toggle", b"overlay param"]);
+        // subscriptions are handled on the clients' own threads
+        while broker.clients.lock().unwrap().iter().map(|c| c.subscriptions.len()).sum::<usize>() < 3 {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let mut publisher = connect(addr, &[]);
+        protocol::Command::Publish(b"overlay param".to_vec(), b"x".to_vec()).write(&mut publisher).unwrap();
+        protocol::Command::Publish(b"overlay toggle".to_vec(), b"adblock".to_vec()).write(&mut publisher).unwrap();
+        let msg = protocol::Message::read(&mut b).unwrap();
+        assert_eq!((msg.event.as_slice(), msg.data.as_slice()), (&b"overlay param"[..], &b"x"[..]));
+        for s in [&mut a, &mut b] {
+            let msg = protocol::Message::read(s).unwrap();
+            assert_eq!((msg.event.as_slice(), msg.data.as_slice()), (&b"overlay toggle"[..], &b"adblock"[..]));
+        }
+    }
+}
@@ -0,0 +1,4 @@
+pub mod protocol;
+pub mod broker;
+
+pub type Erm<T> = Result<T, Box<dyn std::error::Error>>;
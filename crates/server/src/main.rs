@@ -0,0 +1,147 @@
+use std::io::Write;
+use byteorder::{LE, WriteBytesExt};
+use clap::{command, Arg, ArgAction, Command};
+
+use newton_server::{Erm, broker, protocol};
+
+const DEFAULT_ADDR: &str = "127.0.0.1:32051";
+
+#[derive(Debug, Clone)]
+enum Error {
+    BadField(String),
+}
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadField(s) => write!(f, "bad field (expected str:, raw:, hex:, u32:, i32: or f32:): {}", s),
+        }
+    }
+}
+impl std::error::Error for Error {}
+
+/// Encode a message payload from fields like "str:hello" or "u32:0xff0000".
+/// "str" fields are length-prefixed, matching fig::read_length_prefixed_utf8,
+/// while "raw" and "hex" fields are appended as-is.
+fn encode_fields<'a>(fields: impl Iterator<Item = &'a String>) -> Erm<Vec<u8>> {
+    let mut ret = Vec::new();
+    for f in fields {
+        let (ty, val) = f.split_once(':').ok_or_else(|| Error::BadField(f.clone()))?;
+        match ty {
+            "str" => {
+                ret.write_u32::<LE>(val.len() as u32)?;
+                ret.write_all(val.as_bytes())?;
+            },
+            "raw" => ret.write_all(val.as_bytes())?,
+            "hex" => {
+                if val.len() % 2 != 0 || !val.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return Err(Error::BadField(f.clone()).into())
+                }
+                for pair in val.as_bytes().chunks(2) {
+                    ret.write_u8(u8::from_str_radix(std::str::from_utf8(pair)?, 16)?)?;
+                }
+            },
+            "u32" => ret.write_u32::<LE>(match val.strip_prefix("0x") {
+                Some(h) => u32::from_str_radix(h, 16)?,
+                None => val.parse()?,
+            })?,
+            "i32" => ret.write_i32::<LE>(val.parse()?)?,
+            "f32" => ret.write_f32::<LE>(val.parse()?)?,
+            _ => return Err(Error::BadField(f.clone()).into()),
+        }
+    }
+    Ok(ret)
+}
+
+fn addr_arg() -> Arg {
+    Arg::new("addr")
+        .long("addr")
+        .value_name("HOST:PORT")
+        .default_value(DEFAULT_ADDR)
+        .help("Address of the bus")
+}
+
+pub fn main() -> Erm<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let matches = command!()
+        .propagate_version(true)
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommand(
+            Command::new("serve")
+                .about("Run a local fig-compatible message bus")
+                .arg(addr_arg())
+        )
+        .subcommand(
+            Command::new("publish")
+                .about("Publish a message, e.g. publish \"overlay toggle\" raw:adblock")
+                .arg(addr_arg())
+                .arg(Arg::new("event").required(true))
+                .arg(
+                    Arg::new("fields")
+                        .action(ArgAction::Append)
+                        .help("Payload fields: str:TEXT, raw:TEXT, hex:BYTES, u32:N, i32:N or f32:X")
+                )
+        )
+        .subcommand(
+            Command::new("listen")
+                .about("Print messages for the given events")
+                .arg(addr_arg())
+                .arg(Arg::new("events").required(true).action(ArgAction::Append))
+        )
+        .get_matches();
+    match matches.subcommand() {
+        Some(("serve", cm)) => {
+            let addr = cm.get_one::<String>("addr").expect("missing addr");
+            broker::Broker::new().serve(addr)?;
+        },
+        Some(("publish", cm)) => {
+            let addr = cm.get_one::<String>("addr").expect("missing addr");
+            let event = cm.get_one::<String>("event").expect("missing event");
+            let data = encode_fields(cm.get_many::<String>("fields").into_iter().flatten())?;
+            let mut stream = std::net::TcpStream::connect(addr)?;
+            protocol::Command::Publish(event.as_bytes().to_vec(), data).write(&mut stream)?;
+        },
+        Some(("listen", cm)) => {
+            let addr = cm.get_one::<String>("addr").expect("missing addr");
+            let mut stream = std::net::TcpStream::connect(addr)?;
+            for ev in cm.get_many::<String>("events").into_iter().flatten() {
+                protocol::Command::Subscribe(ev.as_bytes().to_vec()).write(&mut stream)?;
+            }
+            loop {
+                let msg = protocol::Message::read(&mut stream)?;
+                println!("{}: {:?}", String::from_utf8_lossy(&msg.event), String::from_utf8_lossy(&msg.data));
+            }
+        },
+        _ => unreachable!("no subcommand"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(fields: &[&str]) -> Erm<Vec<u8>> {
+        let fields: Vec<String> = fields.iter().map(|f| f.to_string()).collect();
+        encode_fields(fields.iter())
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(encode(&["str:hi", "raw:ab", "hex:00ff", "u32:0x10", "i32:-1"]).unwrap(), vec![
+            2, 0, 0, 0, b'h', b'i',
+            b'a', b'b',
+            0x00, 0xff,
+            0x10, 0, 0, 0,
+            0xff, 0xff, 0xff, 0xff,
+        ]);
+        assert_eq!(encode(&["f32:1.5"]).unwrap(), 1.5f32.to_le_bytes());
+    }
+
+    #[test]
+    fn bad_hex() {
+        for f in ["hex:é0", "hex:0", "hex:zz", "hex:+f"] {
+            assert!(encode(&[f]).is_err(), "{f} should not encode");
+        }
+    }
+}
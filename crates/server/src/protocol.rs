@@ -0,0 +1,146 @@
+use std::io::{Read, Write};
+use byteorder::{LE, ReadBytesExt, WriteBytesExt};
+
+use crate::Erm;
+
+// Framing for the fig binary protocol, as spoken by teleia::fig::BinaryClient, which the
+// renderer connects to the bus with (teleia is not part of this tree, so the tests below
+// pin the framing down instead).
+// Every byte string is prefixed with its length as a little-endian u32.
+// Clients send commands tagged with a single byte:
+//   's' <event>         subscribe to an event
+//   'p' <event> <data>  publish a message
+// The server sends each matching message as <event> <data>.
+
+pub const TAG_SUBSCRIBE: u8 = b's';
+pub const TAG_PUBLISH: u8 = b'p';
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    UnknownTag(u8),
+}
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownTag(t) => write!(f, "unknown command tag: {:#04x}", t),
+        }
+    }
+}
+impl std::error::Error for Error {}
+
+#[derive(Debug, Clone)]
+pub enum Command {
+    Subscribe(Vec<u8>),
+    Publish(Vec<u8>, Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub event: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+pub fn read_length_prefixed<R: Read>(r: &mut R) -> Erm<Vec<u8>> {
+    let len = r.read_u32::<LE>()? as usize;
+    let mut ret = vec![0; len];
+    r.read_exact(&mut ret)?;
+    Ok(ret)
+}
+pub fn write_length_prefixed<W: Write>(w: &mut W, bs: &[u8]) -> Erm<()> {
+    w.write_u32::<LE>(bs.len() as u32)?;
+    w.write_all(bs)?;
+    Ok(())
+}
+
+impl Command {
+    pub fn read<R: Read>(r: &mut R) -> Erm<Self> {
+        match r.read_u8()? {
+            TAG_SUBSCRIBE => Ok(Self::Subscribe(read_length_prefixed(r)?)),
+            TAG_PUBLISH => {
+                let event = read_length_prefixed(r)?;
+                let data = read_length_prefixed(r)?;
+                Ok(Self::Publish(event, data))
+            },
+            t => Err(Error::UnknownTag(t).into()),
+        }
+    }
+    pub fn write<W: Write>(&self, w: &mut W) -> Erm<()> {
+        match self {
+            Self::Subscribe(ev) => {
+                w.write_u8(TAG_SUBSCRIBE)?;
+                write_length_prefixed(w, ev)?;
+            },
+            Self::Publish(ev, d) => {
+                w.write_u8(TAG_PUBLISH)?;
+                write_length_prefixed(w, ev)?;
+                write_length_prefixed(w, d)?;
+            },
+        }
+        w.flush()?;
+        Ok(())
+    }
+}
+
+impl Message {
+    pub fn read<R: Read>(r: &mut R) -> Erm<Self> {
+        let event = read_length_prefixed(r)?;
+        let data = read_length_prefixed(r)?;
+        Ok(Self { event, data })
+    }
+    pub fn write<W: Write>(&self, w: &mut W) -> Erm<()> {
+        write_length_prefixed(w, &self.event)?;
+        write_length_prefixed(w, &self.data)?;
+        w.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscribe_frame() {
+        let mut buf = Vec::new();
+        Command::Subscribe(b"overlay toggle".to_vec()).write(&mut buf).unwrap();
+        let mut expected = vec![b's', 14, 0, 0, 0];
+        expected.extend_from_slice(b"overlay toggle");
+        assert_eq!(buf, expected);
+        match Command::read(&mut buf.as_slice()).unwrap() {
+            Command::Subscribe(ev) => assert_eq!(ev, b"overlay toggle"),
+            c => panic!("expected a subscribe, got {:?}", c),
+        }
+    }
+
+    #[test]
+    fn publish_frame() {
+        let mut buf = Vec::new();
+        Command::Publish(b"ev".to_vec(), vec![0, 1, 2]).write(&mut buf).unwrap();
+        assert_eq!(buf, vec![b'p', 2, 0, 0, 0, b'e', b'v', 3, 0, 0, 0, 0, 1, 2]);
+        match Command::read(&mut buf.as_slice()).unwrap() {
+            Command::Publish(ev, data) => {
+                assert_eq!(ev, b"ev");
+                assert_eq!(data, vec![0, 1, 2]);
+            },
+            c => panic!("expected a publish, got {:?}", c),
+        }
+    }
+
+    #[test]
+    fn message_frame() {
+        let mut buf = Vec::new();
+        Message { event: b"ev".to_vec(), data: Vec::new() }.write(&mut buf).unwrap();
+        assert_eq!(buf, vec![2, 0, 0, 0, b'e', b'v', 0, 0, 0, 0]);
+        let msg = Message::read(&mut buf.as_slice()).unwrap();
+        assert_eq!(msg.event, b"ev");
+        assert!(msg.data.is_empty());
+    }
+
+    #[test]
+    fn bad_frames() {
+        assert!(Command::read(&mut [b'x', 0, 0, 0, 0].as_slice()).is_err());
+        // cut off partway through the data
+        assert!(Command::read(&mut [b'p', 1, 0, 0, 0, b'e', 4, 0, 0, 0, 1].as_slice()).is_err());
+        assert!(Message::read(&mut [3, 0, 0].as_slice()).is_err());
+    }
+}